@@ -0,0 +1,255 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{self, PathBuf};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use librespot_metadata::FileFormat;
+
+use crate::config::{Config, DEFAULT_PROFILE};
+use crate::discography::DiscographyFilter;
+use crate::download::OnCollision;
+use crate::retry::RetryPolicy;
+use crate::sanitize;
+use crate::template::Template;
+
+const BITRATES: [u16; 3] = [320, 160, 96];
+const DEFAULT_FILENAME_TEMPLATE: &str = "{artists} - {title}.ogg";
+const DEFAULT_ARTIST_GROUPS: &str = "album,single,compilation";
+
+fn download_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("output-dir")
+            .short("o")
+            .long("output-dir")
+            .value_name("DIR")
+            .help("Directory where the files are written, or the working directory of the helper [default: .]"),
+        Arg::with_name("filename-template")
+            .long("filename-template")
+            .value_name("TEMPLATE")
+            .help("Path of the written files under the output directory, with the fields {title}, {artists}, {artist}, {album}, {album_artist}, {year}, {date}, {disc}, {track} and {id}. Numbers can be zero padded as in {track:02} [default: \"{artists} - {title}.ogg\"]"),
+        Arg::with_name("filename-replacement")
+            .long("filename-replacement")
+            .value_name("CHAR")
+            .help("Replaces the characters that are not allowed in file names [default: _]"),
+        Arg::with_name("on-collision")
+            .long("on-collision")
+            .value_name("POLICY")
+            .possible_values(&["spotify-id", "number", "skip", "overwrite"])
+            .help("What to do when an output file belongs to another track: append the Spotify ID or a number to the new file name, skip the new track or overwrite the file [default: spotify-id]"),
+        Arg::with_name("quality")
+            .short("q")
+            .long("quality")
+            .value_name("KBPS")
+            .help("Preferred Ogg Vorbis bitrate. Lower bitrates are tried next, then higher ones [default: 320]")
+            .possible_values(&["320", "160", "96"]),
+        Arg::with_name("helper")
+            .long("helper")
+            .value_name("PROGRAM")
+            .help("Program invoked for each track with the metadata as arguments and the Ogg stream on stdin"),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .help("HTTP proxy used to connect to Spotify"),
+        Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("FILE")
+            .help("File with one Spotify link or URI per line [default: stdin]"),
+        Arg::with_name("artist-groups")
+            .long("artist-groups")
+            .value_name("GROUPS")
+            .env("OGGIFY_ARTIST_GROUPS")
+            .help("Comma separated album groups an artist link expands to: album, single, compilation, appears-on, top-tracks [default: album,single,compilation]"),
+        Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .value_name("N")
+            .help("Number of tracks downloaded at the same time [default: 1]"),
+        Arg::with_name("force")
+            .long("force")
+            .help("Download again the tracks that were already downloaded"),
+        Arg::with_name("legacy-helper")
+            .value_name("HELPER")
+            .help("Same as --helper")
+            .conflicts_with("helper"),
+    ]
+}
+
+pub fn app() -> App<'static, 'static> {
+    App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .after_help("Without a subcommand, oggify behaves like `oggify download`. \
+                     Defaults for the download options can be set in oggify.toml in the configuration directory.")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("base-path")
+            .long("base-path")
+            .value_name("DIR")
+            .env("OGGIFY_BASE_PATH")
+            .global(true)
+            .help("Directory holding the configuration and cache, instead of the platform default"))
+        .arg(Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .value_name("NAME")
+            .env("OGGIFY_PROFILE")
+            .global(true)
+            .help("Account profile to use, instead of default_profile in oggify.toml"))
+        .args(&download_args())
+        .subcommand(SubCommand::with_name("download")
+            .about("Downloads the tracks, albums, playlists, artists, episodes and shows listed in the input")
+            .args(&download_args()))
+        .subcommand(SubCommand::with_name("login")
+            .about("Asks for username and password and stores reusable credentials"))
+        .subcommand(SubCommand::with_name("logout")
+            .about("Deletes the stored credentials"))
+        .subcommand(SubCommand::with_name("info")
+            .about("Shows the configuration paths and the account of the stored credentials"))
+        .subcommand(SubCommand::with_name("history")
+            .about("Lists the tracks and episodes processed by earlier runs, oldest first")
+            .arg(Arg::with_name("id")
+                .value_name("ID")
+                .help("Only show this track or episode, as a Spotify ID, URI or link"))
+            .arg(Arg::with_name("status")
+                .long("status")
+                .value_name("STATUS")
+                .possible_values(&["downloaded", "skipped", "failed"])
+                .help("Only show the items with this outcome"))
+            .arg(Arg::with_name("substituted")
+                .long("substituted")
+                .help("Only show the tracks replaced by an alternative"))
+            .arg(Arg::with_name("limit")
+                .short("n")
+                .long("limit")
+                .value_name("N")
+                .help("Only show the last N records")))
+        .subcommand(SubCommand::with_name("profiles")
+            .about("Manages the account profiles")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists the profiles, marking the selected one"))
+            .subcommand(SubCommand::with_name("add")
+                .about("Creates a profile and logs in with it")
+                .arg(Arg::with_name("name").required(true)))
+            .subcommand(SubCommand::with_name("remove")
+                .about("Deletes a profile and its credentials")
+                .arg(Arg::with_name("name").required(true))))
+        .subcommand(SubCommand::with_name("config")
+            .about("Inspects the configuration")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("show")
+                .about("Prints the settings resulting from oggify.toml, the given options and the defaults")
+                .args(&download_args())))
+}
+
+/// Applies the command line options in `matches` over `config`, then fills in the defaults.
+pub fn effective_config(matches: &ArgMatches, config: &Config) -> Config {
+    let formats = match matches.value_of("quality") {
+        Some(quality) => {
+            let quality: u16 = quality.parse().unwrap();
+            let position = BITRATES.iter().position(|&bitrate| bitrate == quality).unwrap();
+            let mut formats = BITRATES[position..].to_vec();
+            formats.extend(BITRATES[..position].iter().rev());
+            formats
+        }
+        None => config.formats.clone().unwrap_or_else(|| BITRATES.to_vec()),
+    };
+
+    Config {
+        output_dir: Some(matches.value_of("output-dir").map(PathBuf::from)
+            .or_else(|| config.output_dir.clone())
+            .unwrap_or_else(|| PathBuf::from("."))),
+        filename_template: Some(matches.value_of("filename-template").map(String::from)
+            .or_else(|| config.filename_template.clone())
+            .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_owned())),
+        filename_replacement: Some(matches.value_of("filename-replacement").and_then(|replacement| replacement.chars().next())
+            .or(config.filename_replacement)
+            .unwrap_or('_')),
+        on_collision: Some(matches.value_of("on-collision").map(|policy| policy.parse().unwrap())
+            .or(config.on_collision)
+            .unwrap_or_default()),
+        formats: Some(formats),
+        helper: matches.value_of("helper").or_else(|| matches.value_of("legacy-helper")).map(String::from)
+            .or_else(|| config.helper.clone()),
+        proxy: matches.value_of("proxy").map(String::from).or_else(|| config.proxy.clone()),
+        artist_groups: Some(matches.value_of("artist-groups").map(String::from)
+            .or_else(|| config.artist_groups.clone())
+            .unwrap_or_else(|| DEFAULT_ARTIST_GROUPS.to_owned())),
+        credential_backend: Some(config.credential_backend.unwrap_or_default()),
+        default_profile: Some(config.default_profile.clone().unwrap_or_else(|| DEFAULT_PROFILE.to_owned())),
+        jobs: Some(matches.value_of("jobs").map(|jobs| jobs.parse().unwrap_or(0))
+            .or(config.jobs)
+            .unwrap_or(1)),
+        retry: Some(config.retry.clone().unwrap_or_default()),
+    }
+}
+
+/// Makes a helper given as a path absolute, since it runs in the output directory. A helper given
+/// by name is looked up in `PATH` as is.
+fn helper_path(helper: &str) -> Result<String, String> {
+    if !helper.contains(path::is_separator) {
+        return Ok(helper.to_owned());
+    }
+    let path = fs::canonicalize(helper).map_err(|e| format!("Cannot find helper {}: {}", helper, e))?;
+    path.into_os_string().into_string().map_err(|path| format!("Helper path {} is not valid UTF-8", path.to_string_lossy()))
+}
+
+/// Options of the `download` subcommand.
+pub struct DownloadOptions {
+    pub output_dir: PathBuf,
+    pub template: Template,
+    pub filename_replacement: char,
+    pub on_collision: OnCollision,
+    pub formats: Vec<FileFormat>,
+    pub helper: Option<String>,
+    pub input: Option<PathBuf>,
+    pub artist_filter: DiscographyFilter,
+    pub jobs: usize,
+    /// Whether to download again what an earlier run downloaded
+    pub force: bool,
+    pub retry: RetryPolicy,
+}
+
+impl DownloadOptions {
+    /// Builds the options from the `effective_config` of `matches`.
+    pub fn new(matches: &ArgMatches, config: &Config) -> Result<DownloadOptions, String> {
+        let formats = config.formats.as_ref().unwrap().iter()
+            .map(|bitrate| match bitrate {
+                320 => Ok(FileFormat::OGG_VORBIS_320),
+                160 => Ok(FileFormat::OGG_VORBIS_160),
+                96 => Ok(FileFormat::OGG_VORBIS_96),
+                _ => Err(format!("Unsupported bitrate {}, use 320, 160 or 96", bitrate)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let filename_replacement = config.filename_replacement.unwrap();
+        sanitize::validate_replacement(filename_replacement)?;
+        let jobs = config.jobs.unwrap();
+        if jobs == 0 {
+            return Err("The number of jobs must be a positive integer".to_owned());
+        }
+
+        Ok(DownloadOptions {
+            output_dir: config.output_dir.clone().unwrap(),
+            template: config.filename_template.as_ref().unwrap().parse()?,
+            filename_replacement,
+            on_collision: config.on_collision.unwrap(),
+            formats,
+            helper: config.helper.as_deref().map(helper_path).transpose()?,
+            input: matches.value_of("input").map(PathBuf::from),
+            artist_filter: config.artist_groups.as_ref().unwrap().parse()?,
+            jobs,
+            force: matches.is_present("force"),
+            retry: config.retry.clone().unwrap(),
+        })
+    }
+
+    pub fn open_input(&self) -> Result<Box<dyn BufRead>, String> {
+        match self.input {
+            Some(ref path) => File::open(path)
+                .map(|file| Box::new(BufReader::new(file)) as Box<dyn BufRead>)
+                .map_err(|e| format!("Unable to open {}: {}", path.display(), e)),
+            None => Ok(Box::new(BufReader::new(io::stdin()))),
+        }
+    }
+}
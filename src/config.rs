@@ -5,17 +5,128 @@ use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
 use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::credentials::Backend;
+use crate::download::OnCollision;
+use crate::retry::RetryPolicy;
 
 lazy_static! {
     pub static ref BASE_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
 }
 
+/// Settings read from `oggify.toml`. Fields left unset fall back to the built-in defaults,
+/// and command line flags override them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    pub output_dir: Option<PathBuf>,
+    pub filename_template: Option<String>,
+    /// Replaces the characters that file names cannot contain
+    pub filename_replacement: Option<char>,
+    /// What to do when two items get the same output path
+    pub on_collision: Option<OnCollision>,
+    /// Ogg Vorbis bitrates in order of preference
+    pub formats: Option<Vec<u16>>,
+    pub helper: Option<String>,
+    pub proxy: Option<String>,
+    pub artist_groups: Option<String>,
+    pub credential_backend: Option<Backend>,
+    /// Profile used when `--profile` is not given
+    pub default_profile: Option<String>,
+    /// Tracks downloaded at the same time
+    pub jobs: Option<usize>,
+    // Tables must come after plain values for the TOML serializer
+    pub retry: Option<RetryPolicy>,
+}
+
+/// Version of the `oggify.toml` schema, stored in its `version` key. Files without the
+/// key are version 0.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Migrations of `oggify.toml`, the one at index `n` upgrades version `n` to `n + 1`.
+const CONFIG_MIGRATIONS: [fn(&mut toml::value::Table); CONFIG_VERSION as usize] = [
+    migrate_config_v0,
+];
+
+/// Version 0 only lacks the version key
+fn migrate_config_v0(_: &mut toml::value::Table) {}
+
+fn write_config(path: &Path, config: &Config) -> Result<(), String> {
+    let mut value = toml::Value::try_from(config)
+        .map_err(|e| format!("Failed serializing value: {}", e))?;
+    if let toml::Value::Table(ref mut table) = value {
+        table.insert("version".to_owned(), toml::Value::Integer(CONFIG_VERSION));
+    }
+    write_content_helper(path, value).map(|_| ())
+}
+
+/// Loads `oggify.toml`, upgrading files written by older versions. A file that cannot be
+/// parsed is reported and left untouched.
+pub fn load_config() -> Result<Config, String> {
+    read_config(&config_path("oggify.toml"))
+}
+
+fn read_config(path: &Path) -> Result<Config, String> {
+    if !path.exists() {
+        let config = Config::default();
+        write_config(path, &config)?;
+        return Ok(config);
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let mut value: toml::Value = toml::from_str(&contents).map_err(|e| parse_error(path, &e))?;
+    let table = value.as_table_mut()
+        .ok_or_else(|| format!("Unable to parse {}: not a table", path.display()))?;
+    let version = match table.remove("version") {
+        None => 0,
+        Some(toml::Value::Integer(version)) if version >= 0 => version,
+        Some(version) => return Err(format!("Invalid version {} in {}", version, path.display())),
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "{} has version {}, but this oggify only understands up to version {}",
+            path.display(), version, CONFIG_VERSION
+        ));
+    }
+    for migration in &CONFIG_MIGRATIONS[version as usize..] {
+        migration(table);
+    }
+
+    let config: Config = value.try_into().map_err(|e| parse_error(path, &e))?;
+    if version < CONFIG_VERSION {
+        let backup = backup(path)?;
+        info!("Upgrading {} to version {}, the old file is kept as {}",
+              path.display(), CONFIG_VERSION, backup.display());
+        write_config(path, &config)?;
+    }
+    Ok(config)
+}
+
 fn proj_dirs() -> ProjectDirs {
     match *BASE_PATH.read().expect("can't readlock BASE_PATH") {
         Some(ref basepath) => ProjectDirs::from_path(basepath.clone()).expect("invalid basepath"),
-        None => {
-            ProjectDirs::from("org", "affekt", "ncspot").expect("can't determine project paths")
-        }
+        None => ProjectDirs::from("", "", "oggify").expect("can't determine project paths"),
+    }
+}
+
+/// Copies `file` from the ncspot configuration folder, which older versions used, if it
+/// does not exist in the oggify one yet. The ncspot copy is left alone.
+pub fn migrate_from_ncspot(file: &str) {
+    if BASE_PATH.read().expect("can't readlock BASE_PATH").is_some() {
+        return;
+    }
+    let legacy = match ProjectDirs::from("org", "affekt", "ncspot") {
+        Some(dirs) => dirs.config_dir().join(file),
+        None => return,
+    };
+    let path = config_path(file);
+    if path.exists() || !legacy.exists() {
+        return;
+    }
+    match fs::copy(&legacy, &path) {
+        Ok(_) => info!("Copied {} to {}", legacy.display(), path.display()),
+        Err(e) => warn!("Unable to copy {} to {}: {}", legacy.display(), path.display(), e),
     }
 }
 
@@ -27,13 +138,88 @@ pub fn config_path(file: &str) -> PathBuf {
         fs::remove_file(cfg_dir).expect("unable to remove old config file");
     }
     if !cfg_dir.exists() {
-        fs::create_dir(cfg_dir).expect("can't create config folder");
+        fs::create_dir_all(cfg_dir).expect("can't create config folder");
     }
     let mut cfg = cfg_dir.to_path_buf();
     cfg.push(file);
     cfg
 }
 
+/// The profile whose files live directly in the configuration folder.
+pub const DEFAULT_PROFILE: &str = "default";
+
+fn profiles_dir() -> PathBuf {
+    config_path("profiles")
+}
+
+/// Path of `file` for `profile`. Profiles other than the default one have a folder under
+/// `profiles`, made by `create_profile`.
+pub fn profile_path(profile: &str, file: &str) -> PathBuf {
+    if profile == DEFAULT_PROFILE {
+        return config_path(file);
+    }
+    profiles_dir().join(profile).join(file)
+}
+
+/// Creates the folder of `profile`, which lists it even when its credentials are stored
+/// elsewhere, as with the keyring.
+pub fn create_profile(profile: &str) -> Result<(), String> {
+    if profile == DEFAULT_PROFILE {
+        return Ok(());
+    }
+    let path = profiles_dir().join(profile);
+    fs::create_dir_all(&path).map_err(|e| format!("Unable to create {}: {}", path.display(), e))
+}
+
+/// The default profile and the names of the folders under `profiles`, sorted.
+pub fn profiles() -> Vec<String> {
+    let mut profiles: Vec<_> = fs::read_dir(profiles_dir())
+        .map(|entries| entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect())
+        .unwrap_or_default();
+    profiles.sort();
+    profiles.insert(0, DEFAULT_PROFILE.to_owned());
+    profiles
+}
+
+pub fn remove_profile(profile: &str) -> Result<(), String> {
+    let path = profiles_dir().join(profile);
+    if profile == DEFAULT_PROFILE || !path.exists() {
+        return Ok(());
+    }
+    fs::remove_dir_all(&path).map_err(|e| format!("Unable to delete {}: {}", path.display(), e))
+}
+
+pub fn validate_profile(profile: &str) -> Result<(), String> {
+    if !profile.is_empty() && profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("Invalid profile name {:?}, use letters, digits, - and _", profile))
+    }
+}
+
+pub fn cache_path(dir: &str) -> PathBuf {
+    let proj_dirs = proj_dirs();
+    let mut cache = proj_dirs.cache_dir().to_path_buf();
+    cache.push(dir);
+    if !cache.exists() {
+        fs::create_dir_all(&cache).expect("can't create cache folder");
+    }
+    cache
+}
+
+/// Path of `file` in the data directory, which is created as needed.
+pub fn data_path(file: &str) -> PathBuf {
+    let data_dir = proj_dirs().data_dir().to_path_buf();
+    if !data_dir.exists() {
+        fs::create_dir_all(&data_dir).expect("can't create data folder");
+    }
+    data_dir.join(file)
+}
+
 pub fn load_or_generate_default<
     P: AsRef<Path>,
     T: serde::Serialize + serde::de::DeserializeOwned,
@@ -41,7 +227,6 @@ pub fn load_or_generate_default<
 >(
     path: P,
     default: F,
-    default_on_parse_failure: bool,
 ) -> Result<T, String> {
     let path = path.as_ref();
     // Nothing exists so just write the default and return it
@@ -54,16 +239,35 @@ pub fn load_or_generate_default<
     let contents = std::fs::read_to_string(&path)
         .map_err(|e| format!("Unable to read {}: {}", path.to_string_lossy(), e))?;
 
-    // Deserialize the content, optionally fall back to default if it fails
-    let result = toml::from_str(&contents);
-    if default_on_parse_failure && result.is_err() {
-        let value = default(&path)?;
-        return write_content_helper(&path, value);
+    toml::from_str(&contents).map_err(|e| parse_error(path, &e))
+}
+
+fn parse_error(path: &Path, e: &toml::de::Error) -> String {
+    match e.line_col() {
+        Some((line, col)) => format!(
+            "Unable to parse {} at line {}, column {}: {}",
+            path.display(), line + 1, col + 1, e
+        ),
+        None => format!("Unable to parse {}: {}", path.display(), e),
     }
-    result.map_err(|e| format!("Unable to parse {}: {}", path.to_string_lossy(), e))
 }
 
-fn write_content_helper<P: AsRef<Path>, T: serde::Serialize>(
+/// Copies `path` to the first free name among `<path>.bak`, `<path>.bak.1`, ...
+fn backup(path: &Path) -> Result<PathBuf, String> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    let mut backup = PathBuf::from(&name);
+    let mut n = 0;
+    while backup.exists() {
+        n += 1;
+        backup = PathBuf::from(format!("{}.{}", name.to_string_lossy(), n));
+    }
+    fs::copy(path, &backup)
+        .map(|_| backup.clone())
+        .map_err(|e| format!("Unable to back up {} to {}: {}", path.display(), backup.display(), e))
+}
+
+pub fn write_content_helper<P: AsRef<Path>, T: serde::Serialize>(
     path: P,
     value: T,
 ) -> Result<T, String> {
@@ -79,3 +283,70 @@ fn write_content_helper<P: AsRef<Path>, T: serde::Serialize>(
             )
         })
 }
+
+/// A new empty folder for the files of test `name`.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("oggify-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_takes_the_first_free_name() {
+        let dir = test_dir("backup");
+        let path = dir.join("oggify.toml");
+        fs::write(&path, "jobs = 1\n").unwrap();
+        assert_eq!(backup(&path).unwrap(), dir.join("oggify.toml.bak"));
+        fs::write(&path, "jobs = 2\n").unwrap();
+        assert_eq!(backup(&path).unwrap(), dir.join("oggify.toml.bak.1"));
+        assert_eq!(fs::read_to_string(dir.join("oggify.toml.bak")).unwrap(), "jobs = 1\n");
+        assert_eq!(fs::read_to_string(dir.join("oggify.toml.bak.1")).unwrap(), "jobs = 2\n");
+    }
+
+    #[test]
+    fn parse_errors_have_the_position() {
+        let dir = test_dir("parse-error");
+        let path = dir.join("oggify.toml");
+        fs::write(&path, "jobs = 1\nhelper = \n").unwrap();
+        let error = read_config(&path).unwrap_err();
+        assert!(error.starts_with(&format!("Unable to parse {} at line 2, column ", path.display())), "{}", error);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "jobs = 1\nhelper = \n");
+    }
+
+    #[test]
+    fn upgrades_version_0() {
+        let dir = test_dir("migrate");
+        let path = dir.join("oggify.toml");
+        fs::write(&path, "jobs = 2\n").unwrap();
+        assert_eq!(read_config(&path).unwrap().jobs, Some(2));
+        assert_eq!(fs::read_to_string(dir.join("oggify.toml.bak")).unwrap(), "jobs = 2\n");
+        let upgraded: toml::Value = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(upgraded.get("version").and_then(toml::Value::as_integer), Some(CONFIG_VERSION));
+        assert_eq!(upgraded.get("jobs").and_then(toml::Value::as_integer), Some(2));
+    }
+
+    #[test]
+    fn keeps_current_version() {
+        let dir = test_dir("current");
+        let path = dir.join("oggify.toml");
+        let contents = format!("version = {}\njobs = 3\n", CONFIG_VERSION);
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(read_config(&path).unwrap().jobs, Some(3));
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        assert!(!dir.join("oggify.toml.bak").exists());
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let dir = test_dir("newer");
+        let path = dir.join("oggify.toml");
+        fs::write(&path, format!("version = {}\n", CONFIG_VERSION + 1)).unwrap();
+        assert!(read_config(&path).is_err());
+    }
+}
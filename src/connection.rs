@@ -0,0 +1,63 @@
+use std::future::Future;
+
+use librespot_core::authentication::Credentials;
+use librespot_core::config::SessionConfig;
+use librespot_core::session::Session;
+
+use crate::retry::RetryPolicy;
+
+/// The session of a download run, which is reconnected when the connection to Spotify drops.
+pub struct Connection {
+    session: Session,
+    config: SessionConfig,
+    credentials: Credentials,
+    retry: RetryPolicy,
+}
+
+/// How many times a single operation may reconnect before its failure is final.
+pub const MAX_RECONNECTS: u32 = 3;
+
+async fn connect(config: &SessionConfig, credentials: &Credentials, retry: &RetryPolicy) -> Result<Session, String> {
+    retry.run("Connecting", || Session::connect(config.clone(), credentials.clone(), None, false)).await
+        .map(|(session, _)| session)
+}
+
+impl Connection {
+    pub async fn new(config: SessionConfig, credentials: Credentials, retry: RetryPolicy) -> Result<Connection, String> {
+        let session = connect(&config, &credentials, &retry).await?;
+        Ok(Connection { session, config, credentials, retry })
+    }
+
+    /// The current session, after reconnecting if it died.
+    pub async fn session(&mut self) -> Session {
+        if self.session.is_invalid() {
+            warn!("Connection to Spotify lost, reconnecting...");
+            match connect(&self.config, &self.credentials, &self.retry).await {
+                Ok(session) => {
+                    self.session = session;
+                    info!("Reconnected!");
+                }
+                Err(e) => error!("Cannot reconnect: {}", e),
+            }
+        }
+        self.session.clone()
+    }
+
+    /// Runs `operation` with a live session. If it fails because the session died meanwhile,
+    /// reconnects and runs it again.
+    pub async fn run<T, E, F, R>(&mut self, mut operation: F) -> Result<T, E>
+    where
+        F: FnMut(Session) -> R,
+        R: Future<Output = Result<T, E>>,
+    {
+        let mut reconnects = 0;
+        loop {
+            let session = self.session().await;
+            let result = operation(session.clone()).await;
+            if result.is_ok() || !session.is_invalid() || reconnects >= MAX_RECONNECTS {
+                return result;
+            }
+            reconnects += 1;
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use librespot_core::authentication::Credentials;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Where the reusable credentials are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// `credentials.toml` in the configuration directory, readable only by the user
+    File,
+    /// The desktop keyring, through the Secret Service D-Bus API
+    Keyring,
+    /// `credentials.enc` in the configuration directory, encrypted with a passphrase
+    EncryptedFile,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::File
+    }
+}
+
+fn credentials_fail(_path: &Path) -> Result<Credentials, String> {
+    Err("No credentials found. Run `oggify login` first.".to_string())
+}
+
+fn restrict_permissions(path: &Path) -> Result<(), String> {
+    #[cfg(target_family = "unix")]
+    fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .map_err(|e| format!("Unable to set permissions of {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Writes `contents` to `path`, readable only by the user. The file is never readable by others,
+/// not even before it is complete.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let write_error = |e: std::io::Error| format!("Failed writing content to {}: {}", path.display(), e);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(write_error)?;
+    // An existing file keeps its mode, restrict it before writing anything
+    #[cfg(target_family = "unix")]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600)).map_err(write_error)?;
+    file.write_all(contents).map_err(write_error)
+}
+
+fn remove_file(path: &Path) -> Result<bool, String> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(format!("Unable to delete {}: {}", path.display(), e)),
+    }
+}
+
+pub fn load(backend: Backend, profile: &str) -> Result<Credentials, String> {
+    match backend {
+        Backend::File => {
+            let path = config::profile_path(profile, "credentials.toml");
+            let credentials = config::load_or_generate_default(&path, credentials_fail)?;
+            restrict_permissions(&path)?;
+            Ok(credentials)
+        }
+        Backend::Keyring => keyring::load(profile),
+        Backend::EncryptedFile => encrypted_file::load(profile),
+    }
+}
+
+pub fn store(backend: Backend, profile: &str, credentials: Credentials) -> Result<(), String> {
+    match backend {
+        Backend::File => {
+            let contents = toml::to_string_pretty(&credentials)
+                .map_err(|e| format!("Failed serializing credentials: {}", e))?;
+            config::create_profile(profile)?;
+            write_private(&config::profile_path(profile, "credentials.toml"), contents.as_bytes())
+        }
+        Backend::Keyring => keyring::store(profile, &credentials),
+        Backend::EncryptedFile => encrypted_file::store(profile, &credentials),
+    }
+}
+
+/// Deletes the credentials stored for `profile`, returns whether there were any.
+pub fn delete(backend: Backend, profile: &str) -> Result<bool, String> {
+    match backend {
+        Backend::File => remove_file(&config::profile_path(profile, "credentials.toml")),
+        Backend::Keyring => keyring::delete(profile),
+        Backend::EncryptedFile => remove_file(&config::profile_path(profile, "credentials.enc")),
+    }
+}
+
+/// A description of where `backend` keeps the credentials, for `oggify info`.
+pub fn location(backend: Backend, profile: &str) -> String {
+    match backend {
+        Backend::File => config::profile_path(profile, "credentials.toml").display().to_string(),
+        Backend::Keyring => format!("Secret Service keyring, application \"oggify\", profile \"{}\"", profile),
+        Backend::EncryptedFile => config::profile_path(profile, "credentials.enc").display().to_string(),
+    }
+}
+
+fn to_toml(credentials: &Credentials) -> Result<String, String> {
+    toml::to_string(credentials).map_err(|e| format!("Failed serializing credentials: {}", e))
+}
+
+fn from_toml(contents: &[u8]) -> Result<Credentials, String> {
+    let contents = std::str::from_utf8(contents).map_err(|e| format!("Invalid credentials: {}", e))?;
+    toml::from_str(contents).map_err(|e| format!("Invalid credentials: {}", e))
+}
+
+#[cfg(feature = "keyring")]
+mod keyring {
+    use librespot_core::authentication::Credentials;
+    use secret_service::{EncryptionType, SecretService};
+
+    fn attributes(profile: &str) -> Vec<(&str, &str)> {
+        vec![("application", "oggify"), ("profile", profile)]
+    }
+
+    fn service() -> Result<SecretService, String> {
+        SecretService::new(EncryptionType::Dh).map_err(|e| format!("Cannot connect to the keyring: {}", e))
+    }
+
+    pub fn load(profile: &str) -> Result<Credentials, String> {
+        let service = service()?;
+        let items = service.search_items(attributes(profile))
+            .map_err(|e| format!("Cannot search the keyring: {}", e))?;
+        let item = items.first().ok_or_else(|| "No credentials found. Run `oggify login` first.".to_owned())?;
+        if item.is_locked().map_err(|e| e.to_string())? {
+            item.unlock().map_err(|e| format!("Cannot unlock the keyring: {}", e))?;
+        }
+        let secret = item.get_secret().map_err(|e| format!("Cannot read the keyring: {}", e))?;
+        super::from_toml(&secret)
+    }
+
+    pub fn store(profile: &str, credentials: &Credentials) -> Result<(), String> {
+        let service = service()?;
+        let collection = service.get_default_collection()
+            .map_err(|e| format!("Cannot open the keyring: {}", e))?;
+        if collection.is_locked().map_err(|e| e.to_string())? {
+            collection.unlock().map_err(|e| format!("Cannot unlock the keyring: {}", e))?;
+        }
+        let label = format!("oggify credentials for {} ({})", credentials.username, profile);
+        collection.create_item(&label, attributes(profile), super::to_toml(credentials)?.as_bytes(), true, "text/plain")
+            .map(|_| ())
+            .map_err(|e| format!("Cannot write to the keyring: {}", e))
+    }
+
+    pub fn delete(profile: &str) -> Result<bool, String> {
+        let service = service()?;
+        let items = service.search_items(attributes(profile))
+            .map_err(|e| format!("Cannot search the keyring: {}", e))?;
+        for item in &items {
+            item.delete().map_err(|e| format!("Cannot delete from the keyring: {}", e))?;
+        }
+        Ok(!items.is_empty())
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+mod keyring {
+    use librespot_core::authentication::Credentials;
+
+    const UNSUPPORTED: &str = "oggify was built without keyring support";
+
+    pub fn load(_: &str) -> Result<Credentials, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    pub fn store(_: &str, _: &Credentials) -> Result<(), String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    pub fn delete(_: &str) -> Result<bool, String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+}
+
+/// The encrypted file is the PBKDF2 salt, followed by the nonce and the credentials in TOML
+/// sealed with ChaCha20-Poly1305.
+mod encrypted_file {
+    use super::*;
+
+    const SALT_LEN: usize = 16;
+    const NONCE_LEN: usize = 12;
+    const PBKDF2_ITERATIONS: u32 = 100_000;
+
+    /// The passphrase comes from `OGGIFY_PASSPHRASE`, or is asked on the terminal.
+    fn passphrase() -> Result<String, String> {
+        match std::env::var("OGGIFY_PASSPHRASE") {
+            Ok(passphrase) => Ok(passphrase),
+            Err(_) => rpassword::read_password_from_tty(Some("Credentials passphrase: "))
+                .map_err(|e| format!("Cannot read passphrase: {}", e)),
+        }
+    }
+
+    /// Like `passphrase`, but asks twice on the terminal, so that a typo does not lock the
+    /// credentials away.
+    fn new_passphrase() -> Result<String, String> {
+        if let Ok(passphrase) = std::env::var("OGGIFY_PASSPHRASE") {
+            return Ok(passphrase);
+        }
+        let passphrase = passphrase()?;
+        let confirmation = rpassword::read_password_from_tty(Some("Repeat passphrase: "))
+            .map_err(|e| format!("Cannot read passphrase: {}", e))?;
+        if passphrase != confirmation {
+            return Err("The passphrases do not match".to_owned());
+        }
+        Ok(passphrase)
+    }
+
+    fn key(salt: &[u8], passphrase: &str) -> LessSafeKey {
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap())
+    }
+
+    /// Encrypts `plain` with a new salt and nonce, in the format of the file.
+    fn seal(plain: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+        let rng = SystemRandom::new();
+        let mut header = [0u8; SALT_LEN + NONCE_LEN];
+        rng.fill(&mut header).map_err(|_| "Cannot generate random salt".to_owned())?;
+        let key = key(&header[..SALT_LEN], passphrase);
+        let nonce = Nonce::try_assume_unique_for_key(&header[SALT_LEN..]).unwrap();
+        let mut sealed = plain.to_vec();
+        key.seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| "Cannot encrypt credentials".to_owned())?;
+        let mut contents = header.to_vec();
+        contents.extend(sealed);
+        Ok(contents)
+    }
+
+    /// Decrypts the contents of a file written by `seal`, `None` if they were not sealed with
+    /// `passphrase` or were altered.
+    fn open(mut contents: Vec<u8>, passphrase: &str) -> Option<Vec<u8>> {
+        if contents.len() < SALT_LEN + NONCE_LEN {
+            return None;
+        }
+        let mut sealed = contents.split_off(SALT_LEN + NONCE_LEN);
+        let key = key(&contents[..SALT_LEN], passphrase);
+        let nonce = Nonce::try_assume_unique_for_key(&contents[SALT_LEN..]).unwrap();
+        let len = key.open_in_place(nonce, Aad::empty(), &mut sealed).ok()?.len();
+        sealed.truncate(len);
+        Some(sealed)
+    }
+
+    pub fn load(profile: &str) -> Result<Credentials, String> {
+        let path = config::profile_path(profile, "credentials.enc");
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return credentials_fail(&path),
+            Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+        };
+        if contents.len() < SALT_LEN + NONCE_LEN {
+            return Err(format!("{} is truncated", path.display()));
+        }
+        let plain = open(contents, &passphrase()?)
+            .ok_or_else(|| format!("Cannot decrypt {}, wrong passphrase?", path.display()))?;
+        from_toml(&plain)
+    }
+
+    pub fn store(profile: &str, credentials: &Credentials) -> Result<(), String> {
+        let contents = seal(to_toml(credentials)?.as_bytes(), &new_passphrase()?)?;
+        config::create_profile(profile)?;
+        write_private(&config::profile_path(profile, "credentials.enc"), &contents)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let contents = seal(b"username = \"user\"", "correct horse").unwrap();
+            assert_eq!(contents.len(), SALT_LEN + NONCE_LEN + 17 + aead::CHACHA20_POLY1305.tag_len());
+            assert_eq!(open(contents, "correct horse").unwrap(), b"username = \"user\"");
+        }
+
+        #[test]
+        fn wrong_passphrase() {
+            let contents = seal(b"username = \"user\"", "correct horse").unwrap();
+            assert!(open(contents, "battery staple").is_none());
+        }
+
+        #[test]
+        fn altered_contents() {
+            let mut contents = seal(b"username = \"user\"", "correct horse").unwrap();
+            let last = contents.len() - 1;
+            contents[last] ^= 1;
+            assert!(open(contents, "correct horse").is_none());
+            assert!(open(vec![0; SALT_LEN], "correct horse").is_none());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_round_trip() {
+        let credentials = Credentials::with_password("user".to_owned(), "secret".to_owned());
+        let parsed = from_toml(to_toml(&credentials).unwrap().as_bytes()).unwrap();
+        assert_eq!(parsed.username, "user");
+        assert_eq!(parsed.auth_type, credentials.auth_type);
+        assert_eq!(parsed.auth_data, b"secret");
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn private_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = config::test_dir("private").join("credentials.toml");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn invalid_toml() {
+        assert!(from_toml(b"\xff").is_err());
+        assert!(from_toml(b"username = 1").is_err());
+    }
+}
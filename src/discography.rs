@@ -0,0 +1,113 @@
+use std::str::FromStr;
+
+use librespot_core::session::Session;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_metadata::Metadata;
+use librespot_protocol as protocol;
+
+/// The album groups of an artist, which `librespot_metadata::Artist` does not expose.
+pub struct Discography {
+    pub albums: Vec<SpotifyId>,
+    pub singles: Vec<SpotifyId>,
+    pub compilations: Vec<SpotifyId>,
+    pub appears_on: Vec<SpotifyId>,
+    pub top_tracks: Vec<SpotifyId>,
+}
+
+fn album_group_ids(groups: &[protocol::metadata::AlbumGroup]) -> Vec<SpotifyId> {
+    groups.iter()
+        .flat_map(|group| group.get_album().iter())
+        .filter_map(|album| SpotifyId::from_raw(album.get_gid()).ok())
+        .collect()
+}
+
+impl Metadata for Discography {
+    type Message = protocol::metadata::Artist;
+
+    fn request_url(id: SpotifyId) -> String {
+        format!("hm://metadata/3/artist/{}", id.to_base16())
+    }
+
+    fn parse(msg: &Self::Message, session: &Session) -> Self {
+        let country = session.country();
+        let top_tracks = msg.get_top_track().iter()
+            .find(|tracks| tracks.get_country() == country)
+            .or_else(|| msg.get_top_track().first())
+            .map(|tracks| tracks.get_track().iter()
+                .filter_map(|track| SpotifyId::from_raw(track.get_gid()).ok())
+                .collect())
+            .unwrap_or_default();
+
+        Discography {
+            albums: album_group_ids(msg.get_album_group()),
+            singles: album_group_ids(msg.get_single_group()),
+            compilations: album_group_ids(msg.get_compilation_group()),
+            appears_on: album_group_ids(msg.get_appears_on_group()),
+            top_tracks,
+        }
+    }
+}
+
+/// Which parts of a discography an artist link expands to.
+#[derive(Clone, Copy, Debug)]
+pub struct DiscographyFilter {
+    pub albums: bool,
+    pub singles: bool,
+    pub compilations: bool,
+    pub appears_on: bool,
+    pub top_tracks: bool,
+}
+
+impl Default for DiscographyFilter {
+    fn default() -> Self {
+        DiscographyFilter {
+            albums: true,
+            singles: true,
+            compilations: true,
+            appears_on: false,
+            top_tracks: false,
+        }
+    }
+}
+
+impl FromStr for DiscographyFilter {
+    type Err = String;
+
+    /// Parses a comma separated list of `album`, `single`, `compilation`, `appears-on` and `top-tracks`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = DiscographyFilter {
+            albums: false,
+            singles: false,
+            compilations: false,
+            appears_on: false,
+            top_tracks: false,
+        };
+        for group in s.split(',').map(str::trim).filter(|group| !group.is_empty()) {
+            match group {
+                "album" => filter.albums = true,
+                "single" => filter.singles = true,
+                "compilation" => filter.compilations = true,
+                "appears-on" => filter.appears_on = true,
+                "top-tracks" => filter.top_tracks = true,
+                _ => return Err(format!("Unknown artist group {}", group)),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Discography {
+    /// The album IDs selected by `filter`, in group order.
+    pub fn albums(&self, filter: &DiscographyFilter) -> Vec<SpotifyId> {
+        let groups = [
+            (filter.albums, &self.albums),
+            (filter.singles, &self.singles),
+            (filter.compilations, &self.compilations),
+            (filter.appears_on, &self.appears_on),
+        ];
+        groups.iter()
+            .filter(|(enabled, _)| *enabled)
+            .flat_map(|(_, ids)| ids.iter().cloned())
+            .collect()
+    }
+}
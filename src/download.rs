@@ -0,0 +1,996 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, FutureExt};
+use futures::stream::{self, StreamExt};
+use librespot_audio::{AudioDecrypt, AudioFile};
+use librespot_core::audio_key::AudioKey;
+use librespot_core::session::Session;
+use librespot_core::spotify_id::{FileId, SpotifyId};
+use librespot_metadata::{Album, Artist, FileFormat, Metadata, Show};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::DownloadOptions;
+use crate::config;
+use crate::connection::{Connection, MAX_RECONNECTS};
+use crate::discography::Discography;
+use crate::history::{self, History, Record, Status};
+use crate::input::Link;
+use crate::partial::{self, PartialFile};
+use crate::playlist::Playlist;
+use crate::podcast::Episode;
+use crate::sanitize;
+use crate::track::TrackDetails;
+use crate::vorbis;
+
+/// Why a link or a track could not be downloaded.
+#[derive(Debug)]
+pub enum Error {
+    Metadata(&'static str, SpotifyId, String),
+    Unavailable(SpotifyId),
+    NoOggFormat(SpotifyId),
+    /// With the downloaded file, `None` if it was handed to the helper
+    AlreadyDownloaded(SpotifyId, Option<PathBuf>),
+    /// The output path belongs to the item with the given ID
+    Collision(SpotifyId, PathBuf, String),
+    AudioKey(SpotifyId, String),
+    Fetch(SpotifyId, String),
+    /// No audio data arrived for the given number of seconds
+    Stalled(SpotifyId, u64),
+    Interrupted(SpotifyId),
+    Decrypt(SpotifyId, io::Error),
+    Write(PathBuf, io::Error),
+    Partial(PathBuf, io::Error),
+    Helper(String),
+}
+
+impl Error {
+    /// Whether the item is just not downloadable, rather than failed.
+    pub fn is_skip(&self) -> bool {
+        matches!(self, Error::Unavailable(_) | Error::NoOggFormat(_) | Error::AlreadyDownloaded(..) | Error::Collision(..))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Metadata(what, id, e) => write!(f, "Cannot get {} metadata for {}: {}", what, id.to_base62(), e),
+            Error::Unavailable(id) => write!(f, "Track {} is not available and has no available alternative", id.to_base62()),
+            Error::NoOggFormat(id) => write!(f, "Could not find a OGG_VORBIS format for {}", id.to_base62()),
+            Error::AlreadyDownloaded(id, Some(path)) => write!(f, "{} was already downloaded to {}", id.to_base62(), path.display()),
+            Error::AlreadyDownloaded(id, None) => write!(f, "{} was already handed to the helper", id.to_base62()),
+            Error::Collision(id, path, owner) =>
+                write!(f, "{} would be saved to {}, which belongs to {}", id.to_base62(), path.display(), owner),
+            Error::AudioKey(id, e) => write!(f, "Cannot get audio key for {}: {}", id.to_base62(), e),
+            Error::Fetch(id, e) => write!(f, "Cannot read file stream for {}: {}", id.to_base62(), e),
+            Error::Stalled(id, secs) => write!(f, "No audio data received for {} in {}s", id.to_base62(), secs),
+            Error::Interrupted(id) => write!(f, "Download of {} was interrupted", id.to_base62()),
+            Error::Decrypt(id, e) => write!(f, "Cannot decrypt stream for {}: {}", id.to_base62(), e),
+            Error::Write(path, e) => write!(f, "Cannot write {}: {}", path.display(), e),
+            Error::Partial(path, e) => write!(f, "Cannot save partial download {}: {}", path.display(), e),
+            Error::Helper(e) => write!(f, "Helper program failed: {}", e),
+        }
+    }
+}
+
+/// What was saved for a track or episode.
+pub struct Saved {
+    pub id: SpotifyId,
+    /// The track downloaded instead, when the requested one was unavailable
+    pub alternative: Option<SpotifyId>,
+    pub format: FileFormat,
+    /// Output file, `None` when the stream was handed to the helper
+    pub path: Option<PathBuf>,
+    /// SHA-256 of the Ogg stream, in hex
+    pub checksum: String,
+    /// How a collision with the output path of another item was resolved
+    pub collision: Option<String>,
+}
+
+/// What to do when the output path of an item belongs to another one, `on_collision` in
+/// `oggify.toml`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnCollision {
+    /// Append the Spotify ID, as in `title (<id>).ogg`
+    SpotifyId,
+    /// Append the first free number, as in `title (2).ogg`
+    Number,
+    Skip,
+    Overwrite,
+}
+
+impl Default for OnCollision {
+    fn default() -> Self {
+        OnCollision::SpotifyId
+    }
+}
+
+impl FromStr for OnCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spotify-id" => Ok(OnCollision::SpotifyId),
+            "number" => Ok(OnCollision::Number),
+            "skip" => Ok(OnCollision::Skip),
+            "overwrite" => Ok(OnCollision::Overwrite),
+            _ => Err(format!("Unknown collision policy {}, use spotify-id, number, skip or overwrite", s)),
+        }
+    }
+}
+
+/// Outcome of a whole run.
+#[derive(Default)]
+pub struct Summary {
+    pub succeeded: usize,
+    /// Requested tracks and the alternatives downloaded in their place
+    pub substituted: Vec<(SpotifyId, SpotifyId)>,
+    /// How collisions between output paths were resolved
+    pub collisions: Vec<String>,
+    pub skipped: Vec<Error>,
+    pub failed: Vec<Error>,
+}
+
+impl Summary {
+    pub fn record(&mut self, result: Result<Saved, Error>) {
+        match result {
+            Ok(saved) => {
+                self.succeeded += 1;
+                if let Some(alternative) = saved.alternative {
+                    self.substituted.push((saved.id, alternative));
+                }
+                if let Some(collision) = saved.collision {
+                    warn!("Collision: {}", collision);
+                    self.collisions.push(collision);
+                }
+            }
+            Err(e) => {
+                if e.is_skip() {
+                    warn!("Skipped: {}", e);
+                    self.skipped.push(e);
+                } else {
+                    error!("Failed: {}", e);
+                    self.failed.push(e);
+                }
+            }
+        }
+    }
+
+    pub fn log(&self) {
+        info!("{} succeeded, {} skipped, {} failed", self.succeeded, self.skipped.len(), self.failed.len());
+        for (id, alternative) in &self.substituted {
+            info!("Substituted: {} by {}", id.to_base62(), alternative.to_base62());
+        }
+        for collision in &self.collisions {
+            warn!("Collision: {}", collision);
+        }
+        for e in &self.skipped {
+            info!("Skipped: {}", e);
+        }
+        for e in &self.failed {
+            error!("Failed: {}", e);
+        }
+    }
+}
+
+/// A single item to download, as resolved from an input link.
+#[derive(Clone, Copy)]
+pub enum Media {
+    Track(SpotifyId),
+    Episode(SpotifyId),
+}
+
+impl Media {
+    pub fn id(&self) -> SpotifyId {
+        match *self {
+            Media::Track(id) | Media::Episode(id) => id,
+        }
+    }
+}
+
+async fn get<T: Metadata>(session: &Session, options: &DownloadOptions, what: &'static str, id: SpotifyId) -> Result<T, Error> {
+    options.retry.run_in(session, &format!("Getting {} {}", what, id.to_base62()), || T::get(session, id)).await
+        .map_err(|e| Error::Metadata(what, id, e))
+}
+
+/// Expands `link` into the tracks or episodes it refers to, along with the errors of the albums
+/// of an artist that could not be expanded.
+pub async fn resolve(session: &Session, options: &DownloadOptions, link: Link) -> Result<(Vec<Media>, Vec<Error>), Error> {
+    let mut failed = Vec::new();
+    let media = match link {
+        Link::Track(id) => vec![Media::Track(id)],
+        Link::Album(id) => {
+            info!("Getting album {}...", id.to_base62());
+            let album: Album = get(session, options, "album", id).await?;
+            info!("Album {}: {} tracks", album.name, album.tracks.len());
+            album.tracks.into_iter().map(Media::Track).collect()
+        }
+        Link::Playlist(id) => {
+            info!("Getting playlist {}...", id.to_base62());
+            let playlist: Playlist = get(session, options, "playlist", id).await?;
+            info!("Playlist {}: {} items", playlist.name, playlist.items.len());
+            playlist.items
+        }
+        Link::Artist(id) => {
+            info!("Getting artist {}...", id.to_base62());
+            let discography: Discography = get(session, options, "artist", id).await?;
+            let mut ids = Vec::new();
+            if options.artist_filter.top_tracks {
+                ids.extend(discography.top_tracks.iter().cloned());
+            }
+            for album_id in discography.albums(&options.artist_filter) {
+                match get::<Album>(session, options, "album", album_id).await {
+                    Ok(album) => {
+                        info!("Album {}: {} tracks", album.name, album.tracks.len());
+                        ids.extend(album.tracks);
+                    }
+                    // Every album fails with a dead session, so the artist is resolved again after reconnecting
+                    Err(e) if session.is_invalid() => return Err(e),
+                    Err(e) => failed.push(e),
+                }
+            }
+            let mut seen = HashSet::new();
+            ids.retain(|id|seen.insert(*id));
+            info!("Artist {}: {} tracks", id.to_base62(), ids.len());
+            ids.into_iter().map(Media::Track).collect()
+        }
+        Link::Episode(id) => vec![Media::Episode(id)],
+        Link::Show(id) => {
+            info!("Getting show {}...", id.to_base62());
+            let show: Show = get(session, options, "show", id).await?;
+            info!("Show {}: {} episodes", show.name, show.episodes.len());
+            show.episodes.into_iter().map(Media::Episode).collect()
+        }
+    };
+    Ok((media, failed))
+}
+
+/// Downloads `media` with up to `options.jobs` items in flight, each item once. Output paths are
+/// claimed and the results recorded in `summary` in input order. Items that failed because the session died are downloaded again
+/// after reconnecting. Dropping the future cancels the downloads in progress, except for the saves
+/// on blocking threads, which `cancellation` stops.
+pub async fn download_all(connection: &mut Connection, options: &Arc<DownloadOptions>, cancellation: &Cancellation, history: &History, media: Vec<Media>, summary: &mut Summary) {
+    // The same item twice would write the same files at the same time
+    let count = media.len();
+    let mut seen = HashSet::new();
+    let mut pending: Vec<_> = media.into_iter().filter(|media| seen.insert(media.id())).enumerate().collect();
+    if pending.len() < count {
+        info!("Ignoring {} duplicate items", count - pending.len());
+    }
+    let total = pending.len();
+    let mut reconnects = 0;
+    while !pending.is_empty() {
+        let session = connection.session().await;
+        let can_retry = reconnects < MAX_RECONNECTS;
+        let mut failed_with_session = Vec::new();
+        let order: Vec<usize> = pending.iter().map(|(index, _)| *index).collect();
+        let mut next = 0;
+        let mut finished = HashMap::new();
+        let mut downloads = stream::iter(pending)
+            .map(|(index, media)| {
+                let session = session.clone();
+                async move {
+                    let prepared = prepare(&session, options, history, media).await;
+                    (index, media, prepared, session)
+                }
+            })
+            .buffered(options.jobs)
+            // `buffered` keeps the input order, so that which item gets a contested path does not
+            // depend on which metadata arrives first
+            .map(|(index, media, prepared, session)| {
+                let prepared = prepared.and_then(|prepared| choose_output(options, history, prepared));
+                let cancel = cancellation.token.clone();
+                async move {
+                    let result = match prepared {
+                        Ok(prepared) => download(&session, options, &cancel, prepared).await,
+                        Err(e) => Err(e),
+                    };
+                    (index, media, result, session.is_invalid())
+                }
+            })
+            .buffer_unordered(options.jobs);
+        while let Some((index, media, result, session_died)) = downloads.next().await {
+            finished.insert(index, (media, result, session_died));
+            // A slow item holds back the records of the ones after it, but not their downloads
+            while let Some(&index) = order.get(next) {
+                let (media, result, session_died) = match finished.remove(&index) {
+                    Some(finished) => finished,
+                    None => break,
+                };
+                next += 1;
+                if result.is_err() && session_died && can_retry {
+                    failed_with_session.push((index, media));
+                    continue;
+                }
+                if result.is_ok() {
+                    info!("Done {}/{}", index + 1, total);
+                }
+                if let Err(e) = history.add(history_record(media.id(), &result)) {
+                    warn!("{}", e);
+                }
+                summary.record(result);
+            }
+        }
+        pending = failed_with_session;
+        reconnects += 1;
+    }
+}
+
+fn history_record(id: SpotifyId, result: &Result<Saved, Error>) -> Record {
+    match result {
+        Ok(saved) => Record {
+            alternative: saved.alternative.map(|alternative| alternative.to_base62()),
+            format: Some(format!("{:?}", saved.format)),
+            path: saved.path.clone(),
+            checksum: Some(saved.checksum.clone()),
+            ..Record::new(id, Status::Downloaded)
+        },
+        Err(e) => Record {
+            error: Some(e.to_string()),
+            ..Record::new(id, if e.is_skip() { Status::Skipped } else { Status::Failed })
+        },
+    }
+}
+
+/// An item with its metadata, ready to download.
+struct Prepared {
+    id: SpotifyId,
+    /// The track whose audio is downloaded, an alternative when `id` is unavailable
+    audio_id: SpotifyId,
+    format: FileFormat,
+    file_id: FileId,
+    output: Output,
+    /// How a collision of the output path was resolved
+    collision: Option<String>,
+}
+
+async fn prepare(session: &Session, options: &DownloadOptions, history: &History, media: Media) -> Result<Prepared, Error> {
+    let id = media.id();
+    if !options.force {
+        if let Some(record) = history.lookup(id) {
+            return Err(Error::AlreadyDownloaded(id, record.path));
+        }
+    }
+    match media {
+        Media::Track(id) => prepare_track(session, options, id).await,
+        Media::Episode(id) => prepare_episode(session, options, id).await,
+    }
+}
+
+/// Claims the output path of `prepared`, see `choose_path`.
+fn choose_output(options: &DownloadOptions, history: &History, mut prepared: Prepared) -> Result<Prepared, Error> {
+    if let Output::File(ref mut relative_path, _) = prepared.output {
+        let (chosen, collision) = choose_path(options, history, prepared.id, relative_path)?;
+        *relative_path = chosen;
+        prepared.collision = collision;
+    }
+    Ok(prepared)
+}
+
+async fn download(session: &Session, options: &Arc<DownloadOptions>, cancel: &CancelToken, prepared: Prepared) -> Result<Saved, Error> {
+    let Prepared { id, audio_id, format, file_id, output, collision } = prepared;
+    let (path, checksum) = fetch_and_save(session, options, cancel, audio_id, format, file_id, output).await?;
+    let alternative = Some(audio_id).filter(|&audio_id| audio_id != id);
+    Ok(Saved { id, alternative, format, path, checksum, collision })
+}
+
+/// Picks the output path of `id`, starting from `relative_path`. Fails with `AlreadyDownloaded`
+/// if the file there was already saved for `id`, and applies `options.on_collision` if the path
+/// belongs to another item. Returns the path and, after a collision, a description of it.
+fn choose_path(options: &DownloadOptions, history: &History, id: SpotifyId, relative_path: &Path) -> Result<(PathBuf, Option<String>), Error> {
+    let name = relative_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut candidate = relative_path.to_path_buf();
+    let mut first_owner = None;
+    for number in 2.. {
+        let path = options.output_dir.join(&candidate);
+        // Tagged files name their item even when the history does not know them
+        let tagged = if path.exists() { vorbis::spotify_id(&path) } else { None };
+        let owner = match tagged {
+            Some(tagged) if tagged != id.to_base62() => Err(tagged),
+            _ => history.claim(&path, id, false),
+        };
+        let owner = match owner {
+            Ok(()) if path.exists() && !options.force => {
+                let path = history::absolute(&path);
+                let record = Record { path: Some(path.clone()), ..Record::new(id, Status::Downloaded) };
+                if let Err(e) = history.add(record) {
+                    warn!("{}", e);
+                }
+                return Err(Error::AlreadyDownloaded(id, Some(path)));
+            }
+            Ok(()) => {
+                let collision = first_owner.map(|owner| format!("{} saved as {}, since {} belongs to {}",
+                    id.to_base62(), candidate.display(), relative_path.display(), owner));
+                return Ok((candidate, collision));
+            }
+            Err(owner) => owner,
+        };
+
+        match options.on_collision {
+            OnCollision::Overwrite => {
+                let _ = history.claim(&path, id, true);
+                let collision = format!("{} overwrote {}, which belonged to {}", id.to_base62(), candidate.display(), owner);
+                return Ok((candidate, Some(collision)));
+            }
+            OnCollision::SpotifyId if first_owner.is_none() => {
+                candidate = relative_path.with_file_name(sanitize::with_suffix(&name, &id.to_base62()));
+            }
+            OnCollision::Number => {
+                candidate = relative_path.with_file_name(sanitize::with_suffix(&name, &number.to_string()));
+            }
+            OnCollision::Skip | OnCollision::SpotifyId => return Err(Error::Collision(id, path, owner)),
+        }
+        first_owner.get_or_insert(owner);
+    }
+    unreachable!()
+}
+
+/// Where a downloaded stream goes.
+#[derive(Clone)]
+enum Output {
+    /// Path relative to the output directory, and the Vorbis comments to write
+    File(PathBuf, Vec<vorbis::Comment>),
+    Helper(String, Vec<String>),
+}
+
+/// Rate at which librespot expects a file of `format` to be consumed.
+fn bytes_per_second(format: FileFormat) -> usize {
+    match format {
+        FileFormat::OGG_VORBIS_96 => 12 * 1024,
+        FileFormat::OGG_VORBIS_160 => 20 * 1024,
+        _ => 40 * 1024,
+    }
+}
+
+async fn open_file(session: &Session, options: &DownloadOptions, id: SpotifyId, format: FileFormat, file_id: FileId) -> Result<AudioFile, Error> {
+    let what = format!("Opening audio file for {}", id.to_base62());
+    let file = options.retry.run_in(session, &what, || AudioFile::open(session, file_id, bytes_per_second(format), true)).await
+        .map_err(|e| Error::Fetch(id, e))?;
+    // Fetch the whole file sequentially rather than at playback pace
+    file.get_stream_loader_controller().set_stream_mode();
+    Ok(file)
+}
+
+/// Stops the saves that run on blocking threads, which dropping `download_all` does not.
+pub struct Cancellation {
+    token: CancelToken,
+    /// Receives nothing, and is closed once every token is dropped
+    finished: tokio::sync::mpsc::Receiver<()>,
+}
+
+/// Held by each save, tells it to stop and `Cancellation::cancel` when it is over.
+#[derive(Clone)]
+struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    _alive: tokio::sync::mpsc::Sender<()>,
+}
+
+impl CancelToken {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Cancellation {
+    fn default() -> Self {
+        let (alive, finished) = tokio::sync::mpsc::channel(1);
+        Cancellation { token: CancelToken { cancelled: Arc::new(AtomicBool::new(false)), _alive: alive }, finished }
+    }
+}
+
+impl Cancellation {
+    /// Stops the saves at their next chunk and waits up to `timeout` for them to delete their
+    /// temporary files.
+    pub async fn cancel(self, timeout: Duration) {
+        let Cancellation { token, mut finished } = self;
+        token.cancelled.store(true, Ordering::SeqCst);
+        drop(token);
+        let _ = tokio::time::timeout(timeout, finished.recv()).await;
+    }
+}
+
+lazy_static! {
+    /// Locks of the audio files downloaded in this run, which share their partial download in
+    /// the cache.
+    static ref IN_FLIGHT: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+/// Waits for exclusive use of the partial download of an audio file, released when the guard is
+/// dropped. Different items can have the same audio file, an alternative track for instance.
+async fn lock_file(file_id: FileId) -> tokio::sync::OwnedMutexGuard<()> {
+    let lock = IN_FLIGHT.lock().unwrap().entry(file_id.to_base16()).or_default().clone();
+    lock.lock_owned().await
+}
+
+/// Gets the key and opens the audio file, then reads, decrypts and saves it on a blocking thread.
+/// A download that stalls is opened again and resumed, up to `retry.attempts` times.
+/// Returns the path of the written file, if it was not handed to the helper, and the checksum.
+async fn fetch_and_save(session: &Session, options: &Arc<DownloadOptions>, cancel: &CancelToken, id: SpotifyId, format: FileFormat, file_id: FileId, output: Output) -> Result<(Option<PathBuf>, String), Error> {
+    let _in_flight = lock_file(file_id).await;
+    let key_what = format!("Getting audio key for {}", id.to_base62());
+    let key = options.retry.run_in(session, &key_what, || session.audio_key().request(id, file_id))
+        .map(|result| result.map_err(|e| Error::AudioKey(id, e)));
+    let (key, mut encrypted_file) = future::try_join(key, open_file(session, options, id, format, file_id)).await?;
+    for attempt in 1.. {
+        let (save_options, save_cancel, save_output) = (options.clone(), cancel.clone(), output.clone());
+        let saved = tokio::task::spawn_blocking(move || save(&save_options, &save_cancel, id, file_id, key, encrypted_file, save_output)).await
+            .map_err(|e| Error::Fetch(id, e.to_string()))?;
+        match saved {
+            Err(e @ Error::Stalled(..)) if attempt < options.retry.attempts && !session.is_invalid() => {
+                warn!("{}, resuming ({}/{})", e, attempt, options.retry.attempts);
+                encrypted_file = open_file(session, options, id, format, file_id).await?;
+            }
+            saved => return saved,
+        }
+    }
+    unreachable!()
+}
+
+/// Length of the Spotify header that precedes the Ogg stream.
+const SPOTIFY_HEADER_LEN: usize = 0xa7;
+
+fn save(options: &DownloadOptions, cancel: &CancelToken, id: SpotifyId, file_id: FileId, key: AudioKey, mut encrypted_file: AudioFile, output: Output) -> Result<(Option<PathBuf>, String), Error> {
+    let size = encrypted_file.get_stream_loader_controller().len() as u64;
+    // The encrypted file goes to the cache first, so that an interrupted download can resume
+    let mut partial = PartialFile::open(file_id, size).map_err(|e| Error::Partial(config::cache_path("partial"), e))?;
+    let offset = partial.downloaded();
+    if offset > 0 {
+        info!("Resuming {} at {}/{} bytes", id.to_base62(), offset, size);
+    }
+    encrypted_file.seek(SeekFrom::Start(offset)).map_err(|e| Error::Fetch(id, e.to_string()))?;
+    download_encrypted(options, cancel, id, encrypted_file, &mut partial)?;
+    let encrypted = partial.finish().map_err(|e| {
+        partial::discard(file_id);
+        Error::Fetch(id, e)
+    })?;
+
+    // Decrypt while reading, so that only one chunk at a time is held in memory
+    let mut decrypted = AudioDecrypt::new(key, BufReader::new(encrypted));
+    let mut header = [0u8; SPOTIFY_HEADER_LEN];
+    decrypted.read_exact(&mut header).map_err(|e| Error::Decrypt(id, e))?;
+    // The checksum covers the stream as written, so with the comments for files
+    let (path, checksum) = match output {
+        Output::File(relative_path, comments) => {
+            let mut stream = Checksum::new(vorbis::Tagger::new(decrypted, comments));
+            (Some(write_file(options, cancel, id, &relative_path, &mut stream)?), stream.hex())
+        }
+        Output::Helper(helper, args) => {
+            let mut stream = Checksum::new(decrypted);
+            run_helper(options, cancel, id, &helper, &args, &mut stream)?;
+            (None, stream.hex())
+        }
+    };
+    partial::discard(file_id);
+    Ok((path, checksum))
+}
+
+/// Copies `encrypted_file` to `partial` from a separate thread. A read of an `AudioFile` waits for
+/// its data without a time limit, so the copy fails with `Stalled` when no data arrives for
+/// `retry.timeout_secs`, and with `Interrupted` once cancelled. The reader thread is then left
+/// blocked until the process exits.
+fn download_encrypted(options: &DownloadOptions, cancel: &CancelToken, id: SpotifyId, mut encrypted_file: AudioFile, partial: &mut PartialFile) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::sync_channel(4);
+    thread::spawn(move || {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = match encrypted_file.read(&mut chunk) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                read => read.map(|len| chunk[..len].to_vec()),
+            };
+            let last = read.as_ref().map_or(true, |data| data.is_empty());
+            if sender.send(read).is_err() || last {
+                return;
+            }
+        }
+    });
+
+    let partial_path = partial.path().clone();
+    let timeout = Duration::from_secs(options.retry.timeout_secs);
+    let mut last_data = Instant::now();
+    loop {
+        // Wake up regularly to notice a cancellation
+        let stopped = match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(Ok(data)) if data.is_empty() => break,
+            Ok(Ok(data)) => {
+                partial.write_all(&data).map_err(|e| Error::Partial(partial_path.clone(), e))?;
+                last_data = Instant::now();
+                None
+            }
+            Ok(Err(e)) => return Err(Error::Fetch(id, e.to_string())),
+            Err(RecvTimeoutError::Timeout) if last_data.elapsed() >= timeout => Some(Error::Stalled(id, timeout.as_secs())),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Fetch(id, "reader thread stopped".to_owned())),
+        };
+        let stopped = if cancel.is_cancelled() { Some(Error::Interrupted(id)) } else { stopped };
+        if let Some(e) = stopped {
+            // Keep what arrived for the next attempt or run
+            partial.flush().map_err(|e| Error::Partial(partial_path.clone(), e))?;
+            return Err(e);
+        }
+    }
+    partial.flush().map_err(|e| Error::Partial(partial_path, e))
+}
+
+/// A reader computing the SHA-256 of what is read through it.
+struct Checksum<R> {
+    inner: R,
+    context: digest::Context,
+}
+
+impl<R: Read> Checksum<R> {
+    fn new(inner: R) -> Self {
+        Checksum { inner, context: digest::Context::new(&digest::SHA256) }
+    }
+
+    fn hex(self) -> String {
+        self.context.finish().as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl<R: Read> Read for Checksum<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.context.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Size of the reads from the audio streams.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Copies `stream` to `writer` in chunks, telling apart read errors from write errors.
+fn copy_stream(cancel: &CancelToken, id: SpotifyId, stream: &mut impl Read, writer: &mut impl Write, write_error: impl Fn(io::Error) -> Error) -> Result<(), Error> {
+    let mut chunk = [0u8; CHUNK_SIZE];
+    loop {
+        if cancel.is_cancelled() {
+            return Err(Error::Interrupted(id));
+        }
+        let len = match stream.read(&mut chunk) {
+            Ok(0) => return writer.flush().map_err(write_error),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Fetch(id, e.to_string())),
+        };
+        writer.write_all(&chunk[..len]).map_err(&write_error)?;
+    }
+}
+
+/// Ending of the temporary files that are renamed to the output files once complete.
+const TEMP_SUFFIX: &str = ".oggify-tmp";
+
+/// Numbers the temporary files of this process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary files not modified for this long belong to interrupted runs.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3600);
+
+/// Writes `stream` to a temporary file next to the output file, then syncs it and renames it into
+/// place, so that the output file is either missing or complete.
+fn write_file(options: &DownloadOptions, cancel: &CancelToken, id: SpotifyId, relative_path: &Path, stream: &mut impl Read) -> Result<PathBuf, Error> {
+    let path = options.output_dir.join(relative_path);
+    let dir = path.parent().unwrap_or(&options.output_dir).to_path_buf();
+    fs::create_dir_all(&dir).map_err(|e| Error::Write(dir.clone(), e))?;
+    let first_write = CLEANED_DIRS.lock().unwrap().insert(dir.clone());
+    if first_write {
+        clean_temporaries(&dir);
+    }
+    // A short name, as the output file name may already be as long as file names can be
+    let counter = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let temp_path = dir.join(format!(".{}-{}{}", std::process::id(), counter, TEMP_SUFFIX));
+
+    let written = File::create(&temp_path)
+        .map_err(|e| Error::Write(temp_path.clone(), e))
+        .and_then(|mut file| {
+            copy_stream(cancel, id, stream, &mut file, |e| Error::Write(temp_path.clone(), e))?;
+            file.sync_all().map_err(|e| Error::Write(temp_path.clone(), e))
+        })
+        .and_then(|_| fs::rename(&temp_path, &path).map_err(|e| Error::Write(path.clone(), e)));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    // Make the rename itself durable
+    #[cfg(target_family = "unix")]
+    File::open(&dir).and_then(|dir| dir.sync_all()).map_err(|e| Error::Write(dir.clone(), e))?;
+    info!("Filename: {}", relative_path.display());
+    Ok(fs::canonicalize(&path).unwrap_or(path))
+}
+
+lazy_static! {
+    /// Directories written to in this run, whose stale temporary files were deleted.
+    static ref CLEANED_DIRS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Deletes the temporary files that interrupted runs left in `dir`.
+fn clean_temporaries(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        // Recent ones may belong to another oggify running in the same directory
+        let stale = metadata.modified().ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(true, |age| age > STALE_TEMP_AGE);
+        if path.to_string_lossy().ends_with(TEMP_SUFFIX) && stale {
+            match fs::remove_file(&path) {
+                Ok(()) => info!("Deleted stale temporary file {}", path.display()),
+                Err(e) => warn!("Unable to delete {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+fn run_helper(options: &DownloadOptions, cancel: &CancelToken, id: SpotifyId, helper: &str, args: &[String], stream: &mut impl Read) -> Result<(), Error> {
+    fs::create_dir_all(&options.output_dir)
+        .map_err(|e| Error::Helper(format!("Could not create {}: {}", options.output_dir.display(), e)))?;
+    let mut cmd = Command::new(helper);
+    cmd.current_dir(&options.output_dir);
+    cmd.stdin(Stdio::piped());
+    cmd.args(args);
+    let mut child = cmd.spawn().map_err(|e| Error::Helper(format!("Could not run {}: {}", helper, e)))?;
+    let mut pipe = child.stdin.take().expect("Could not open helper stdin");
+    let copied = copy_stream(cancel, id, stream, &mut pipe, |e| Error::Helper(format!("Failed to write to stdin: {}", e)));
+    // Close stdin so that the helper sees the end of the stream
+    drop(pipe);
+    if let Err(e) = copied {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+    let status = child.wait().map_err(|e| Error::Helper(e.to_string()))?;
+    if !status.success() {
+        return Err(Error::Helper(format!("{} returned {}", helper, status)));
+    }
+    Ok(())
+}
+
+/// Returns `details`, or the details of the first available alternative.
+async fn find_available(session: &Session, options: &DownloadOptions, id: SpotifyId, details: TrackDetails) -> Result<TrackDetails, Error> {
+    if details.track.available {
+        return Ok(details);
+    }
+    warn!("Track {} is not available, finding alternative...", id.to_base62());
+    for alt_id in details.track.alternatives {
+        let candidate: TrackDetails = get(session, options, "track", alt_id).await?;
+        if candidate.track.available {
+            warn!("Found track alternative {} -> {}", id.to_base62(), candidate.track.id.to_base62());
+            return Ok(candidate);
+        }
+    }
+    Err(Error::Unavailable(id))
+}
+
+/// The first file of `files` in the preferred formats.
+fn find_file<'a>(options: &DownloadOptions, files: impl IntoIterator<Item = (&'a FileFormat, &'a FileId)>) -> Option<(FileFormat, FileId)> {
+    let files: Vec<_> = files.into_iter().collect();
+    debug!("File formats: {}", files.iter().map(|(filetype, _)|format!("{:?}", filetype)).collect::<Vec<_>>().join(" "));
+    options.formats.iter()
+        .find_map(|format| files.iter().find(|(filetype, _)| *filetype == format))
+        .map(|(format, file_id)| (**format, **file_id))
+}
+
+/// The values of the file name template for a track.
+fn track_fields(id: SpotifyId, details: &TrackDetails, artists: &[String]) -> HashMap<&'static str, String> {
+    let mut fields = HashMap::new();
+    fields.insert("title", details.track.name.clone());
+    fields.insert("artists", artists.join(", "));
+    fields.insert("artist", artists.first().cloned().unwrap_or_default());
+    fields.insert("album", details.album.clone());
+    fields.insert("album_artist", details.album_artists.first().or_else(|| artists.first()).cloned().unwrap_or_default());
+    fields.insert("year", details.date.chars().take(4).collect());
+    fields.insert("date", details.date.clone());
+    fields.insert("disc", details.disc_number.to_string());
+    fields.insert("track", details.number.to_string());
+    fields.insert("id", id.to_base62());
+    fields
+}
+
+/// The values of the file name template for an episode, where the show takes the place of the
+/// album and the artists.
+fn episode_fields(id: SpotifyId, episode: &Episode, show: &Show) -> HashMap<&'static str, String> {
+    let mut fields = HashMap::new();
+    fields.insert("title", episode.name.clone());
+    fields.insert("artists", show.name.clone());
+    fields.insert("artist", show.name.clone());
+    fields.insert("album", show.name.clone());
+    fields.insert("album_artist", show.publisher.clone());
+    if let Some(ref date) = episode.publish_date {
+        fields.insert("year", date.chars().take(4).collect());
+        fields.insert("date", date.clone());
+    }
+    fields.insert("id", id.to_base62());
+    fields
+}
+
+/// The Vorbis comments of a track. `SPOTIFY_ID` is the requested ID, so that the file is found
+/// again when a substitute was downloaded.
+fn track_comments(id: SpotifyId, details: &TrackDetails, artists: &[String]) -> Vec<vorbis::Comment> {
+    let mut comments = vec![("TITLE".to_owned(), details.track.name.clone())];
+    comments.extend(artists.iter().map(|artist| ("ARTIST".to_owned(), artist.clone())));
+    comments.push(("ALBUM".to_owned(), details.album.clone()));
+    comments.extend(details.album_artists.iter().map(|artist| ("ALBUMARTIST".to_owned(), artist.clone())));
+    if !details.date.is_empty() {
+        comments.push(("DATE".to_owned(), details.date.clone()));
+    }
+    comments.push(("TRACKNUMBER".to_owned(), details.number.to_string()));
+    comments.push(("DISCNUMBER".to_owned(), details.disc_number.to_string()));
+    if let Some(ref isrc) = details.isrc {
+        comments.push(("ISRC".to_owned(), isrc.clone()));
+    }
+    comments.push(("SPOTIFY_ID".to_owned(), id.to_base62()));
+    comments
+}
+
+/// The Vorbis comments of an episode, with the show as album and the publisher as artist.
+fn episode_comments(id: SpotifyId, episode: &Episode, show: &Show) -> Vec<vorbis::Comment> {
+    let mut comments = vec![
+        ("TITLE".to_owned(), episode.name.clone()),
+        ("ARTIST".to_owned(), show.publisher.clone()),
+        ("ALBUM".to_owned(), show.name.clone()),
+    ];
+    if let Some(ref date) = episode.publish_date {
+        comments.push(("DATE".to_owned(), date.clone()));
+    }
+    comments.push(("SPOTIFY_ID".to_owned(), id.to_base62()));
+    comments
+}
+
+async fn prepare_track(session: &Session, options: &DownloadOptions, id: SpotifyId) -> Result<Prepared, Error> {
+    info!("Getting track {}...", id.to_base62());
+    let details: TrackDetails = get(session, options, "track", id).await?;
+    let details = find_available(session, options, id, details).await?;
+    let track = &details.track;
+    let artists = future::try_join_all(track.artists.iter()
+        .map(|artist_id| get::<Artist>(session, options, "artist", *artist_id))).await?;
+    let artists_strs: Vec<_> = artists.into_iter().map(|artist| artist.name).collect();
+    let (format, file_id) = find_file(options, &track.files).ok_or(Error::NoOggFormat(track.id))?;
+    let output = match options.helper {
+        Some(ref helper) => {
+            let mut args = vec![id.to_base62(), track.name.clone(), details.album.clone(), details.date.clone()];
+            args.extend(artists_strs);
+            Output::Helper(helper.clone(), args)
+        }
+        None => {
+            let relative_path = options.template.render(&track_fields(id, &details, &artists_strs), options.filename_replacement);
+            Output::File(relative_path, track_comments(id, &details, &artists_strs))
+        }
+    };
+    Ok(Prepared { id, audio_id: track.id, format, file_id, output, collision: None })
+}
+
+async fn prepare_episode(session: &Session, options: &DownloadOptions, id: SpotifyId) -> Result<Prepared, Error> {
+    info!("Getting episode {}...", id.to_base62());
+    let episode: Episode = get(session, options, "episode", id).await?;
+    let show_id = episode.show.ok_or_else(|| Error::Metadata("episode", id, "no valid show ID".to_owned()))?;
+    let show: Show = get(session, options, "show", show_id).await?;
+    let (format, file_id) = find_file(options, &episode.files).ok_or(Error::NoOggFormat(id))?;
+    let output = match options.helper {
+        Some(ref helper) => {
+            let date = episode.publish_date.clone().unwrap_or_default();
+            Output::Helper(helper.clone(), vec![id.to_base62(), episode.name, show.name, date, show.publisher])
+        }
+        None => Output::File(options.template.render(&episode_fields(id, &episode, &show), options.filename_replacement), episode_comments(id, &episode, &show)),
+    };
+    Ok(Prepared { id, audio_id: id, format, file_id, output, collision: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryPolicy;
+
+    fn id(n: u8) -> SpotifyId {
+        SpotifyId::from_raw(&[n; 16]).unwrap()
+    }
+
+    fn options(dir: &Path, on_collision: OnCollision) -> DownloadOptions {
+        DownloadOptions {
+            output_dir: dir.to_path_buf(),
+            template: "{title}.ogg".parse().unwrap(),
+            filename_replacement: '_',
+            on_collision,
+            formats: vec![FileFormat::OGG_VORBIS_160],
+            helper: None,
+            input: None,
+            artist_filter: "album".parse().unwrap(),
+            jobs: 1,
+            force: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// A history in `dir` where `files` were downloaded there for their IDs.
+    fn history_with(dir: &Path, files: &[(&str, SpotifyId)]) -> History {
+        let history = History::open(dir.join("history.jsonl")).unwrap();
+        for &(name, id) in files {
+            let path = dir.join(name);
+            fs::write(&path, "not ogg").unwrap();
+            history.add(Record { path: Some(history::absolute(&path)), ..Record::new(id, Status::Downloaded) }).unwrap();
+        }
+        history
+    }
+
+    #[test]
+    fn free_path() {
+        let dir = config::test_dir("choose-free");
+        let history = history_with(&dir, &[]);
+        let (path, collision) = choose_path(&options(&dir, OnCollision::Number), &history, id(1), Path::new("Song.ogg")).unwrap();
+        assert_eq!((path, collision), (PathBuf::from("Song.ogg"), None));
+        // The same item can claim its path again, another one cannot
+        assert!(choose_path(&options(&dir, OnCollision::Number), &history, id(1), Path::new("Song.ogg")).is_ok());
+        assert!(matches!(choose_path(&options(&dir, OnCollision::Skip), &history, id(2), Path::new("Song.ogg")),
+            Err(Error::Collision(..))));
+    }
+
+    #[test]
+    fn already_downloaded() {
+        let dir = config::test_dir("choose-downloaded");
+        let history = history_with(&dir, &[("Song.ogg", id(1))]);
+        assert!(matches!(choose_path(&options(&dir, OnCollision::Number), &history, id(1), Path::new("Song.ogg")),
+            Err(Error::AlreadyDownloaded(_, Some(_)))));
+    }
+
+    #[test]
+    fn owner_file_gone() {
+        let dir = config::test_dir("choose-gone");
+        let history = history_with(&dir, &[("Song.ogg", id(1))]);
+        fs::remove_file(dir.join("Song.ogg")).unwrap();
+        let (path, collision) = choose_path(&options(&dir, OnCollision::Skip), &history, id(2), Path::new("Song.ogg")).unwrap();
+        assert_eq!((path, collision), (PathBuf::from("Song.ogg"), None));
+    }
+
+    #[test]
+    fn number_suffix_collides_again() {
+        let dir = config::test_dir("choose-number");
+        let history = history_with(&dir, &[("Song.ogg", id(1)), ("Song (2).ogg", id(2))]);
+        let (path, collision) = choose_path(&options(&dir, OnCollision::Number), &history, id(3), Path::new("Song.ogg")).unwrap();
+        assert_eq!(path, PathBuf::from("Song (3).ogg"));
+        assert!(collision.unwrap().ends_with(&format!("belongs to {}", id(1).to_base62())));
+    }
+
+    #[test]
+    fn spotify_id_suffix_collides_again() {
+        let dir = config::test_dir("choose-spotify-id");
+        let suffixed = format!("Song ({}).ogg", id(3).to_base62());
+        let history = history_with(&dir, &[("Song.ogg", id(1))]);
+        let (path, _) = choose_path(&options(&dir, OnCollision::SpotifyId), &history, id(3), Path::new("Song.ogg")).unwrap();
+        assert_eq!(path, PathBuf::from(&suffixed));
+
+        let dir = config::test_dir("choose-spotify-id-again");
+        let history = history_with(&dir, &[("Song.ogg", id(1)), (suffixed.as_str(), id(2))]);
+        let result = choose_path(&options(&dir, OnCollision::SpotifyId), &history, id(3), Path::new("Song.ogg"));
+        assert!(matches!(result, Err(Error::Collision(_, _, owner)) if owner == id(2).to_base62()));
+    }
+
+    #[test]
+    fn overwrite() {
+        let dir = config::test_dir("choose-overwrite");
+        let history = history_with(&dir, &[("Song.ogg", id(1))]);
+        let (path, collision) = choose_path(&options(&dir, OnCollision::Overwrite), &history, id(2), Path::new("Song.ogg")).unwrap();
+        assert_eq!(path, PathBuf::from("Song.ogg"));
+        assert!(collision.is_some());
+        assert!(matches!(choose_path(&options(&dir, OnCollision::Skip), &history, id(1), Path::new("Song.ogg")),
+            Err(Error::Collision(..))));
+    }
+}
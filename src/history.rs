@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use librespot_core::spotify_id::SpotifyId;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Downloaded,
+    Skipped,
+    Failed,
+}
+
+/// One line of the history, describing what happened to an item in a run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// The requested track or episode
+    pub id: String,
+    /// The track downloaded instead, when the requested one was unavailable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alternative: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Output file, missing for items handed to the helper
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// SHA-256 of the Ogg stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(id: SpotifyId, status: Status) -> Record {
+        Record {
+            id: id.to_base62(),
+            alternative: None,
+            format: None,
+            path: None,
+            checksum: None,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            status,
+            error: None,
+        }
+    }
+}
+
+/// The items processed by all runs, kept as an append-only log of JSON lines in
+/// `history.jsonl` in the data directory.
+pub struct History {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The last successful download of each ID
+    downloaded: HashMap<String, Record>,
+    /// The ID each output path was last saved for
+    owners: HashMap<PathBuf, String>,
+    /// The ID each output path is reserved for in this run
+    claims: HashMap<PathBuf, String>,
+}
+
+impl State {
+    fn remember(&mut self, record: Record) {
+        if record.status == Status::Downloaded {
+            if let Some(ref path) = record.path {
+                self.owners.insert(path.clone(), record.id.clone());
+            }
+            self.downloaded.insert(record.id.clone(), record);
+        }
+    }
+}
+
+/// `path` made absolute and free of symbolic links, as far as it exists, so that the same file
+/// is always found under the same path.
+pub fn absolute(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+    match (path.parent().and_then(|parent| fs::canonicalize(parent).ok()), path.file_name()) {
+        (Some(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<Record>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+    };
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A line cut short by a crash is not worth stopping for
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Ignoring line {} of {}: {}", number + 1, path.display(), e),
+        }
+    }
+    Ok(records)
+}
+
+impl History {
+    pub fn load() -> Result<History, String> {
+        History::open(config::data_path("history.jsonl"))
+    }
+
+    /// The history kept in `path`.
+    pub fn open(path: PathBuf) -> Result<History, String> {
+        let history = History { path, state: Mutex::new(State::default()) };
+        {
+            let mut state = history.state.lock().unwrap();
+            for record in read_records(&history.path)? {
+                state.remember(record);
+            }
+        }
+        Ok(history)
+    }
+
+    /// The last download of `id`, if its file is still there and was not overwritten for another
+    /// item since.
+    pub fn lookup(&self, id: SpotifyId) -> Option<Record> {
+        let state = self.state.lock().unwrap();
+        let record = state.downloaded.get(&id.to_base62())?;
+        match record.path {
+            Some(ref path) if !path.exists() || state.owners.get(path) != Some(&record.id) => None,
+            _ => Some(record.clone()),
+        }
+    }
+
+    pub fn add(&self, record: Record) -> Result<(), String> {
+        let line = serde_json::to_string(&record).map_err(|e| format!("Cannot serialize history: {}", e))?;
+        // Keep the lock while appending, so that lines of concurrent downloads do not mix
+        let mut state = self.state.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| format!("Unable to open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("Unable to write {}: {}", self.path.display(), e))?;
+        state.remember(record);
+        Ok(())
+    }
+
+    /// Reserves `path` for `id` in this run. Fails with the ID of the item that owns the path,
+    /// if another item of this run claimed it or the file there was saved for another item.
+    /// With `force`, takes the path from its owner.
+    pub fn claim(&self, path: &Path, id: SpotifyId, force: bool) -> Result<(), String> {
+        let path = absolute(path);
+        let id = id.to_base62();
+        let mut state = self.state.lock().unwrap();
+        let owner = state.claims.get(&path)
+            .or_else(|| state.owners.get(&path).filter(|_| path.exists()));
+        match owner {
+            Some(owner) if *owner != id && !force => Err(owner.clone()),
+            _ => {
+                state.claims.insert(path, id);
+                Ok(())
+            }
+        }
+    }
+
+    /// All the records, oldest first.
+    pub fn records(&self) -> Result<Vec<Record>, String> {
+        read_records(&self.path)
+    }
+}
+
+/// Formats `timestamp` as a UTC date and time.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+    // Civil date from the days since 1970-01-01, after Howard Hinnant's days_from_civil inverse
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> SpotifyId {
+        SpotifyId::from_raw(&[n; 16]).unwrap()
+    }
+
+    fn downloaded(history: &History, id: SpotifyId, path: &Path) {
+        history.add(Record { path: Some(path.to_path_buf()), ..Record::new(id, Status::Downloaded) }).unwrap();
+    }
+
+    #[test]
+    fn claims() {
+        let dir = config::test_dir("history-claims");
+        let history = History::open(dir.join("history.jsonl")).unwrap();
+        let path = absolute(&dir.join("song.ogg"));
+        assert_eq!(history.claim(&path, id(1), false), Ok(()));
+        assert_eq!(history.claim(&path, id(1), false), Ok(()));
+        assert_eq!(history.claim(&path, id(2), false), Err(id(1).to_base62()));
+        assert_eq!(history.claim(&path, id(2), true), Ok(()));
+        assert_eq!(history.claim(&path, id(1), false), Err(id(2).to_base62()));
+    }
+
+    #[test]
+    fn owners_need_their_file() {
+        let dir = config::test_dir("history-owners");
+        let history = History::open(dir.join("history.jsonl")).unwrap();
+        let path = absolute(&dir.join("song.ogg"));
+        downloaded(&history, id(1), &path);
+        assert!(history.lookup(id(1)).is_none());
+        assert_eq!(history.claim(&path, id(2), false), Ok(()));
+
+        // A later run finds the file and its owner
+        fs::write(&path, "ogg").unwrap();
+        let history = History::open(dir.join("history.jsonl")).unwrap();
+        assert_eq!(history.lookup(id(1)).and_then(|record| record.path), Some(path.clone()));
+        assert_eq!(history.claim(&path, id(2), false), Err(id(1).to_base62()));
+    }
+
+    #[test]
+    fn overwritten_files_are_forgotten() {
+        let dir = config::test_dir("history-overwritten");
+        let history = History::open(dir.join("history.jsonl")).unwrap();
+        let path = absolute(&dir.join("song.ogg"));
+        fs::write(&path, "ogg").unwrap();
+        downloaded(&history, id(1), &path);
+        downloaded(&history, id(2), &path);
+        assert!(history.lookup(id(1)).is_none());
+        assert!(history.lookup(id(2)).is_some());
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_709_210_096), "2024-02-29 12:34:56");
+    }
+
+    #[test]
+    fn after_2100() {
+        // 2100 is not a leap year
+        assert_eq!(format_timestamp(4_107_542_399), "2100-02-28 23:59:59");
+        assert_eq!(format_timestamp(4_107_542_400), "2100-03-01 00:00:00");
+    }
+}
@@ -0,0 +1,94 @@
+use librespot_core::spotify_id::SpotifyId;
+use regex::Regex;
+
+lazy_static! {
+    static ref SPOTIFY_URI: Regex =
+        Regex::new(r"spotify:(?:user:[^:\s]+:)?(track|album|playlist|artist|episode|show):([[:alnum:]]+)").unwrap();
+    static ref SPOTIFY_URL: Regex =
+        Regex::new(r"open\.spotify\.com/(?:user/[^/\s]+/)?(track|album|playlist|artist|episode|show)/([[:alnum:]]+)")
+            .unwrap();
+}
+
+/// A Spotify resource found in a line of input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+    Track(SpotifyId),
+    Album(SpotifyId),
+    Playlist(SpotifyId),
+    Artist(SpotifyId),
+    Episode(SpotifyId),
+    Show(SpotifyId),
+}
+
+impl Link {
+    pub fn id(&self) -> SpotifyId {
+        match *self {
+            Link::Track(id) | Link::Album(id) | Link::Playlist(id) | Link::Artist(id) | Link::Episode(id) | Link::Show(id) => id,
+        }
+    }
+}
+
+/// Looks for a Spotify URI or URL in `line`. Legacy user playlist forms
+/// (`spotify:user:<user>:playlist:<id>`) are accepted too.
+pub fn parse_link(line: &str) -> Option<Link> {
+    let capture = SPOTIFY_URI.captures(line).or_else(|| SPOTIFY_URL.captures(line))?;
+    let id = SpotifyId::from_base62(&capture[2]).ok()?;
+    match &capture[1] {
+        "track" => Some(Link::Track(id)),
+        "album" => Some(Link::Album(id)),
+        "playlist" => Some(Link::Playlist(id)),
+        "artist" => Some(Link::Artist(id)),
+        "episode" => Some(Link::Episode(id)),
+        "show" => Some(Link::Show(id)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6rqhFgbbKwnb9MLmUQDhG6";
+
+    fn id() -> SpotifyId {
+        SpotifyId::from_base62(ID).unwrap()
+    }
+
+    #[test]
+    fn parses_uris() {
+        assert_eq!(parse_link(&format!("spotify:track:{}", ID)), Some(Link::Track(id())));
+        assert_eq!(parse_link(&format!("spotify:album:{}", ID)), Some(Link::Album(id())));
+        assert_eq!(parse_link(&format!("spotify:playlist:{}", ID)), Some(Link::Playlist(id())));
+        assert_eq!(parse_link(&format!("spotify:artist:{}", ID)), Some(Link::Artist(id())));
+        assert_eq!(parse_link(&format!("spotify:episode:{}", ID)), Some(Link::Episode(id())));
+        assert_eq!(parse_link(&format!("spotify:show:{}", ID)), Some(Link::Show(id())));
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(parse_link(&format!("https://open.spotify.com/track/{}", ID)), Some(Link::Track(id())));
+        assert_eq!(parse_link(&format!("https://open.spotify.com/album/{}?si=a1B2c3", ID)), Some(Link::Album(id())));
+        assert_eq!(parse_link(&format!("open.spotify.com/playlist/{}?si=a1B2c3&nd=1", ID)), Some(Link::Playlist(id())));
+        assert_eq!(parse_link(&format!("https://open.spotify.com/artist/{}", ID)), Some(Link::Artist(id())));
+        assert_eq!(parse_link(&format!("https://open.spotify.com/episode/{}?si=x", ID)), Some(Link::Episode(id())));
+        assert_eq!(parse_link(&format!("https://open.spotify.com/show/{}", ID)), Some(Link::Show(id())));
+    }
+
+    #[test]
+    fn parses_legacy_user_playlists() {
+        assert_eq!(parse_link(&format!("spotify:user:some.user:playlist:{}", ID)), Some(Link::Playlist(id())));
+        assert_eq!(parse_link(&format!("https://open.spotify.com/user/some.user/playlist/{}", ID)), Some(Link::Playlist(id())));
+    }
+
+    #[test]
+    fn finds_links_within_lines() {
+        assert_eq!(parse_link(&format!("  Song title - spotify:track:{}  ", ID)), Some(Link::Track(id())));
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        assert_eq!(parse_link(""), None);
+        assert_eq!(parse_link("spotify:local:Artist:Album:Title:180"), None);
+        assert_eq!(parse_link(&format!("spotify:genre:{}", ID)), None);
+    }
+}
@@ -1,142 +1,316 @@
+extern crate clap;
 extern crate env_logger;
+extern crate futures;
 extern crate librespot_audio;
 extern crate librespot_core;
 extern crate librespot_metadata;
+extern crate librespot_protocol;
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate lazy_static;
+extern crate rand;
 extern crate regex;
-extern crate scoped_threadpool;
-extern crate tokio_core;
+extern crate ring;
+extern crate rpassword;
+#[cfg(feature = "keyring")]
+extern crate secret_service;
+extern crate tokio;
 extern crate serde;
+extern crate serde_json;
+extern crate toml;
+extern crate unicode_normalization;
+extern crate url;
 
-use std::env;
-use std::io::{self, BufRead, Read, Result};
+use std::io::{self, BufRead};
 use std::io::Write;
-use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use env_logger::{Builder, Env};
-use librespot_audio::{AudioDecrypt, AudioFile};
 use librespot_core::authentication::Credentials;
 use librespot_core::config::SessionConfig;
 use librespot_core::session::Session;
-use librespot_core::spotify_id::SpotifyId;
-use librespot_metadata::{Artist, FileFormat, Metadata, Track, Album};
-use regex::Regex;
-use scoped_threadpool::Pool;
-use tokio_core::reactor::Core;
-
-use std::path::Path;
+use url::Url;
 
+mod cli;
 mod config;
+mod connection;
+mod credentials;
+mod discography;
+mod download;
+mod history;
+mod input;
+mod partial;
+mod playlist;
+mod podcast;
+mod retry;
+mod sanitize;
+mod template;
+mod track;
+mod vorbis;
+
+use clap::ArgMatches;
+use cli::DownloadOptions;
+use config::Config;
+use connection::Connection;
+use credentials::Backend;
+use download::Summary;
+use history::{History, Status};
+
+fn get_credentials(backend: Backend, profile: &str) -> Credentials {
+    credentials::load(backend, profile).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
 
-fn credentials_fail(_path: &Path) -> std::result::Result<Credentials, String> {
-    Err("No credentials found.".to_string())
+fn session_config(proxy: Option<&str>) -> SessionConfig {
+    let proxy = proxy.map(|proxy| Url::parse(proxy).unwrap_or_else(|e| {
+        eprintln!("Invalid proxy {}: {}", proxy, e);
+        std::process::exit(1);
+    }));
+    SessionConfig { proxy, ..SessionConfig::default() }
 }
 
-fn get_credentials(reset: bool) -> Credentials {
-    let path = config::config_path("credentials.toml");
-    if reset && std::fs::remove_file(&path).is_err() {
-        error!("could not delete credential file");
+async fn download(options: &Arc<DownloadOptions>, config: &Config, profile: &str) {
+    let reader = options.open_input().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let session_config = session_config(config.proxy.as_deref());
+    let credentials = get_credentials(config.credential_backend.unwrap_or_default(), profile);
+    info!("Connecting ...");
+    let mut connection = Connection::new(session_config, credentials, options.retry.clone()).await
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot connect: {}", e);
+            std::process::exit(1);
+        });
+    info!("Connected!");
+
+    let history = History::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut summary = Summary::default();
+    let mut media = Vec::new();
+    for link in reader.lines()
+        .filter_map(|line|
+            line.ok().and_then(|str|
+                input::parse_link(&str)
+                    .or_else(|| { warn!("Cannot parse Spotify link from string {}", str); None }))) {
+        match connection.run(|session| async move { download::resolve(&session, options, link).await }).await {
+            Ok((resolved, failed)) => {
+                media.extend(resolved);
+                failed.into_iter().for_each(|e| summary.record(Err(e)));
+            }
+            Err(e) => summary.record(Err(e)),
+        }
+    }
+
+    // On Ctrl-C the downloads in progress are dropped, and the summary covers what finished
+    let cancellation = download::Cancellation::default();
+    let interrupted = tokio::select! {
+        _ = download::download_all(&mut connection, options, &cancellation, &history, media, &mut summary) => false,
+        _ = tokio::signal::ctrl_c() => true,
+    };
+    if interrupted {
+        warn!("Interrupted, stopping the downloads in progress (Ctrl-C again to quit now)");
+        tokio::select! {
+            _ = cancellation.cancel(Duration::from_secs(10)) => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+
+    summary.log();
+    if interrupted {
+        std::process::exit(130);
     }
+    if !summary.failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+async fn login(config: &Config, profile: &str) {
+    print!("Username: ");
+    io::stdout().flush().unwrap();
+    let mut username = String::new();
+    io::stdin().read_line(&mut username).expect("Cannot read username");
+    let password = rpassword::read_password_from_tty(Some("Password: ")).expect("Cannot read password");
+    let credentials = Credentials::with_password(username.trim().to_owned(), password);
 
-    let creds = config::load_or_generate_default(&path, credentials_fail, true)
+    // Spotify answers a successful login with reusable credentials
+    info!("Connecting ...");
+    let (session, reusable) = Session::connect(session_config(config.proxy.as_deref()), credentials, None, false).await
         .unwrap_or_else(|e| {
-            eprintln!("{}", e);
+            eprintln!("Login failed: {}", e);
             std::process::exit(1);
         });
+    info!("Logged in as {}", session.username());
 
-    #[cfg(target_family = "unix")]
-    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))
+    let backend = config.credential_backend.unwrap_or_default();
+    credentials::store(backend, profile, reusable)
+        .and_then(|_| config::create_profile(profile))
         .unwrap_or_else(|e| {
             eprintln!("{}", e);
             std::process::exit(1);
         });
+    info!("Credentials saved to {}", credentials::location(backend, profile));
+}
 
-    creds
+fn logout(config: &Config, profile: &str) {
+    let backend = config.credential_backend.unwrap_or_default();
+    match credentials::delete(backend, profile) {
+        Ok(true) => info!("Deleted credentials from {}", credentials::location(backend, profile)),
+        Ok(false) => info!("No credentials stored"),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn main() {
-    Builder::from_env(Env::default().default_filter_or("info")).init();
+fn show_info(config: &Config, profile: &str) {
+    let backend = config.credential_backend.unwrap_or_default();
+    println!("Configuration directory: {}", config::config_path("oggify.toml").parent().unwrap().display());
+    println!("Profile: {}", profile);
+    println!("Credentials: {}", credentials::location(backend, profile));
+    match credentials::load(backend, profile) {
+        Ok(credentials) => println!("Account: {}", credentials.username),
+        Err(e) => println!("Account: none ({})", e),
+    }
+}
 
-    let args: Vec<_> = env::args().collect();
-    assert!(args.len() == 1 || args.len() == 2, "Usage: {} user password [helper_script] < tracks_file", args[0]);
+async fn manage_profiles(matches: &ArgMatches, config: &Config, profile: &str) {
+    match matches.subcommand() {
+        ("list", _) => for name in config::profiles() {
+            println!("{} {}", if name == profile { "*" } else { " " }, name);
+        },
+        ("add", Some(add_matches)) => {
+            let name = add_matches.value_of("name").unwrap();
+            validate_profile(name);
+            if config::profiles().iter().any(|existing| existing == name) {
+                eprintln!("Profile {} already exists", name);
+                std::process::exit(1);
+            }
+            login(config, name).await;
+        }
+        ("remove", Some(remove_matches)) => {
+            let name = remove_matches.value_of("name").unwrap();
+            validate_profile(name);
+            logout(config, name);
+            config::remove_profile(name).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        }
+        _ => unreachable!(),
+    }
+}
 
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let session_config = SessionConfig::default();
-    let credentials = get_credentials(false);
-    info!("Connecting ...");
-    let session = core
-        .run(Session::connect(session_config, credentials, None, handle))
-        .unwrap();
-    info!("Connected!");
+fn validate_profile(profile: &str) {
+    config::validate_profile(profile).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+}
 
-    let mut threadpool = Pool::new(1);
+/// Looks up a global option, which clap stores in the matches of the subcommand it follows.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    matches.value_of(name).or_else(|| matches.subcommand().1.and_then(|submatches| global_value(submatches, name)))
+}
 
-    let spotify_uri = Regex::new(r"spotify:track:([[:alnum:]]+)").unwrap();
-    let spotify_url = Regex::new(r"open\.spotify\.com/track/([[:alnum:]]+)").unwrap();
+fn show_history(matches: &ArgMatches) {
+    let history = History::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let records = history.records().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let id = matches.value_of("id").map(|id| match input::parse_link(id) {
+        Some(link) => link.id().to_base62(),
+        None => id.to_owned(),
+    });
+    let status = matches.value_of("status").map(|status| match status {
+        "downloaded" => Status::Downloaded,
+        "skipped" => Status::Skipped,
+        _ => Status::Failed,
+    });
+    let mut records: Vec<_> = records.into_iter()
+        .filter(|record| id.as_ref().map_or(true, |id| record.id == *id || record.alternative.as_ref() == Some(id)))
+        .filter(|record| status.map_or(true, |status| record.status == status))
+        .filter(|record| !matches.is_present("substituted") || record.alternative.is_some())
+        .collect();
+    if let Some(limit) = matches.value_of("limit") {
+        let limit: usize = limit.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid limit {}", limit);
+            std::process::exit(1);
+        });
+        records.drain(..records.len().saturating_sub(limit));
+    }
 
-    io::stdin().lock().lines()
-        .filter_map(|line|
-            line.ok().and_then(|str|
-                spotify_uri.captures(&str).or(spotify_url.captures(&str))
-                    .or_else(|| { warn!("Cannot parse track from string {}", str); None })
-                    .and_then(|capture|SpotifyId::from_base62(&capture[1]).ok())))
-        .for_each(|id|{
-            info!("Getting track {}...", id.to_base62());
-            let mut track = core.run(Track::get(&session, id)).expect("Cannot get track metadata");
-            if !track.available {
-                warn!("Track {} is not available, finding alternative...", id.to_base62());
-                let alt_track = track.alternatives.iter().find_map(|id|{
-                    let alt_track = core.run(Track::get(&session, *id)).expect("Cannot get track metadata");
-                    match alt_track.available {
-                        true => Some(alt_track),
-                        false => None
-                    }
-                });
-                track = alt_track.expect(&format!("Could not find alternative for track {}", id.to_base62()));
-                warn!("Found track alternative {} -> {}", id.to_base62(), track.id.to_base62());
-            }
-            let artists_strs: Vec<_> = track.artists.iter().map(|id|core.run(Artist::get(&session, *id)).expect("Cannot get artist metadata").name).collect();
-            debug!("File formats: {}", track.files.keys().map(|filetype|format!("{:?}", filetype)).collect::<Vec<_>>().join(" "));
-            let file_id = track.files.get(&FileFormat::OGG_VORBIS_320)
-                .or(track.files.get(&FileFormat::OGG_VORBIS_160))
-                .or(track.files.get(&FileFormat::OGG_VORBIS_96))
-                .expect("Could not find a OGG_VORBIS format for the track.");
-            let key = core.run(session.audio_key().request(track.id, *file_id)).expect("Cannot get audio key");
-            let mut encrypted_file = core.run(AudioFile::open(&session, *file_id)).unwrap();
-            let mut buffer = Vec::new();
-            let mut read_all: Result<usize> = Ok(0);
-            let fetched = AtomicBool::new(false);
-            threadpool.scoped(|scope|{
-                scope.execute(||{
-                    read_all = encrypted_file.read_to_end(&mut buffer);
-                    fetched.store(true, Ordering::Release);
-                });
-                while !fetched.load(Ordering::Acquire) {
-                    core.turn(Some(Duration::from_millis(100)));
-                }
+    for record in records {
+        let status = format!("{:?}", record.status).to_lowercase();
+        let mut line = format!("{} {} {}", history::format_timestamp(record.timestamp), status, record.id);
+        if let Some(alternative) = record.alternative {
+            line += &format!(" -> {}", alternative);
+        }
+        if let Some(format) = record.format {
+            line += &format!(" {}", format);
+        }
+        match (record.path, record.error) {
+            (_, Some(error)) => line += &format!(": {}", error),
+            (Some(path), None) => line += &format!(": {}", path.display()),
+            (None, None) => {}
+        }
+        println!("{}", line);
+    }
+}
+
+fn show_config(matches: &ArgMatches, config: &Config) {
+    let effective = cli::effective_config(matches, config);
+    print!("{}", toml::to_string_pretty(&effective).expect("Cannot serialize configuration"));
+}
+
+#[tokio::main]
+async fn main() {
+    Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let matches = cli::app().get_matches();
+    if let Some(base_path) = global_value(&matches, "base-path") {
+        *config::BASE_PATH.write().expect("can't writelock BASE_PATH") = Some(base_path.into());
+    }
+    config::migrate_from_ncspot("credentials.toml");
+    let config = config::load_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let profile = global_value(&matches, "profile").map(String::from)
+        .or_else(|| config.default_profile.clone())
+        .unwrap_or_else(|| config::DEFAULT_PROFILE.to_owned());
+    validate_profile(&profile);
+    match matches.subcommand() {
+        ("login", _) => login(&config, &profile).await,
+        ("logout", _) => logout(&config, &profile),
+        ("info", _) => show_info(&config, &profile),
+        ("history", Some(submatches)) => show_history(submatches),
+        ("profiles", Some(submatches)) => manage_profiles(submatches, &config, &profile).await,
+        ("config", Some(submatches)) => match submatches.subcommand() {
+            ("show", Some(show_matches)) => show_config(show_matches, &config),
+            _ => unreachable!(),
+        },
+        (command, submatches) => {
+            let matches = if command == "download" { submatches.unwrap() } else { &matches };
+            let config = cli::effective_config(matches, &config);
+            let options = DownloadOptions::new(matches, &config).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
             });
-            read_all.expect("Cannot read file stream");
-            let mut decrypted_buffer = Vec::new();
-            AudioDecrypt::new(key, &buffer[..]).read_to_end(&mut decrypted_buffer).expect("Cannot decrypt stream");
-            if args.len() == 1 {
-                let fname = format!("{} - {}.ogg", artists_strs.join(", "), track.name);
-                std::fs::write(&fname, &decrypted_buffer[0xa7..]).expect("Cannot write decrypted track");
-                info!("Filename: {}", fname);
-            } else {
-                let album = core.run(Album::get(&session, track.album)).expect("Cannot get album metadata");
-                let mut cmd = Command::new(args[1].to_owned());
-                cmd.stdin(Stdio::piped());
-                cmd.arg(id.to_base62()).arg(track.name).arg(album.name).arg(album.date.to_string()).args(artists_strs.iter());
-                let mut child = cmd.spawn().expect("Could not run helper program");
-                let pipe = child.stdin.as_mut().expect("Could not open helper stdin");
-                pipe.write_all(&decrypted_buffer[0xa7..]).expect("Failed to write to stdin");
-                assert!(child.wait().expect("Out of ideas for error messages").success(), "Helper script returned an error");
-            }
-        });
+            download(&Arc::new(options), &config, &profile).await;
+        }
+    }
 }
+
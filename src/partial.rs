@@ -0,0 +1,198 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use librespot_core::spotify_id::FileId;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Bytes written between two saves of the progress.
+const CHECKPOINT: u64 = 1024 * 1024;
+
+/// What is known to be on disk, saved next to the data as `<file id>.toml`.
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    file_id: String,
+    size: u64,
+    /// Length of the data synced to disk, from the start of the file
+    downloaded: u64,
+}
+
+/// An encrypted audio file being downloaded to the `partial` cache directory, so that an
+/// interrupted run can resume it.
+pub struct PartialFile {
+    data_path: PathBuf,
+    progress_path: PathBuf,
+    progress: Progress,
+    file: File,
+    unsaved: u64,
+}
+
+fn paths(dir: &Path, file_id: FileId) -> (PathBuf, PathBuf) {
+    let name = file_id.to_base16();
+    (dir.join(format!("{}.part", name)), dir.join(format!("{}.toml", name)))
+}
+
+/// Deletes the partial download of `file_id`, if any.
+pub fn discard(file_id: FileId) {
+    let (data_path, progress_path) = paths(&config::cache_path("partial"), file_id);
+    for path in &[data_path, progress_path] {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Unable to delete {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl PartialFile {
+    /// Opens the partial download of `file_id`, starting over unless it belongs to a file of `size`
+    /// bytes. Data written after the last saved progress is dropped.
+    pub fn open(file_id: FileId, size: u64) -> io::Result<PartialFile> {
+        PartialFile::open_in(&config::cache_path("partial"), file_id, size)
+    }
+
+    fn open_in(dir: &Path, file_id: FileId, size: u64) -> io::Result<PartialFile> {
+        let (data_path, progress_path) = paths(dir, file_id);
+        let progress = fs::read_to_string(&progress_path).ok()
+            .and_then(|contents| toml::from_str::<Progress>(&contents).ok())
+            .filter(|progress| progress.file_id == file_id.to_base16() && progress.size == size)
+            .unwrap_or_else(|| Progress { file_id: file_id.to_base16(), size, downloaded: 0 });
+
+        let mut partial = PartialFile {
+            file: OpenOptions::new().read(true).write(true).create(true).open(&data_path)?,
+            data_path,
+            progress_path,
+            progress,
+            unsaved: 0,
+        };
+        // Data shorter than recorded means the progress cannot be trusted
+        if partial.file.metadata()?.len() < partial.progress.downloaded {
+            partial.progress.downloaded = 0;
+        }
+        partial.file.set_len(partial.progress.downloaded)?;
+        partial.file.seek(SeekFrom::Start(partial.progress.downloaded))?;
+        Ok(partial)
+    }
+
+    /// Length of the data downloaded from the start of the file.
+    pub fn downloaded(&self) -> u64 {
+        self.progress.downloaded
+    }
+
+    fn save_progress(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        let contents = toml::to_string(&self.progress).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        fs::write(&self.progress_path, contents)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Checks that the whole file was downloaded, and returns it for reading from the start.
+    pub fn finish(mut self) -> Result<File, String> {
+        self.save_progress().map_err(|e| format!("Cannot save {}: {}", self.data_path.display(), e))?;
+        let downloaded = self.downloaded();
+        if downloaded != self.progress.size {
+            return Err(format!("expected {} bytes, got {}", self.progress.size, downloaded));
+        }
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| format!("Cannot read {}: {}", self.data_path.display(), e))?;
+        Ok(self.file)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.data_path
+    }
+}
+
+impl Write for PartialFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.progress.downloaded += len as u64;
+        self.unsaved += len as u64;
+        if self.unsaved >= CHECKPOINT {
+            self.save_progress()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.save_progress()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const FILE_ID: FileId = FileId([7; 20]);
+
+    fn read_all(mut file: File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn resumes_and_finishes() {
+        let dir = config::test_dir("partial-resume");
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 10).unwrap();
+        partial.write_all(b"01234").unwrap();
+        partial.flush().unwrap();
+        drop(partial);
+
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 10).unwrap();
+        assert_eq!(partial.downloaded(), 5);
+        partial.write_all(b"56789").unwrap();
+        assert_eq!(read_all(partial.finish().unwrap()), b"0123456789");
+    }
+
+    #[test]
+    fn truncates_to_the_last_checkpoint() {
+        let dir = config::test_dir("partial-checkpoint");
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 2 * CHECKPOINT).unwrap();
+        partial.write_all(&vec![1; CHECKPOINT as usize]).unwrap();
+        partial.write_all(&[2; 100]).unwrap();
+        drop(partial);
+
+        let partial = PartialFile::open_in(&dir, FILE_ID, 2 * CHECKPOINT).unwrap();
+        assert_eq!(partial.downloaded(), CHECKPOINT);
+        assert_eq!(fs::metadata(partial.path()).unwrap().len(), CHECKPOINT);
+    }
+
+    #[test]
+    fn restarts_on_size_mismatch() {
+        let dir = config::test_dir("partial-size");
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 10).unwrap();
+        partial.write_all(b"01234").unwrap();
+        partial.flush().unwrap();
+        drop(partial);
+
+        let partial = PartialFile::open_in(&dir, FILE_ID, 20).unwrap();
+        assert_eq!(partial.downloaded(), 0);
+        assert_eq!(fs::metadata(partial.path()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn restarts_when_data_is_missing() {
+        let dir = config::test_dir("partial-missing");
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 10).unwrap();
+        partial.write_all(b"01234").unwrap();
+        partial.flush().unwrap();
+        let path = partial.path().clone();
+        drop(partial);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(3).unwrap();
+
+        assert_eq!(PartialFile::open_in(&dir, FILE_ID, 10).unwrap().downloaded(), 0);
+    }
+
+    #[test]
+    fn finish_checks_the_size() {
+        let dir = config::test_dir("partial-finish");
+        let mut partial = PartialFile::open_in(&dir, FILE_ID, 10).unwrap();
+        partial.write_all(b"01234").unwrap();
+        assert_eq!(partial.finish().unwrap_err(), "expected 10 bytes, got 5");
+    }
+}
@@ -0,0 +1,66 @@
+use librespot_core::session::Session;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_metadata::Metadata;
+use librespot_protocol as protocol;
+
+use crate::download::Media;
+
+/// A playlist. Unlike `librespot_metadata::Playlist`, it keeps episodes apart from tracks and
+/// skips the items it cannot download, such as local files, instead of panicking.
+pub struct Playlist {
+    pub name: String,
+    pub items: Vec<Media>,
+}
+
+/// The track or episode of a playlist item URI, `None` for local files and other kinds.
+fn parse_item(uri: &str) -> Option<Media> {
+    let mut parts = uri.split(':');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("spotify"), Some("track"), Some(id), None) => SpotifyId::from_base62(id).ok().map(Media::Track),
+        (Some("spotify"), Some("episode"), Some(id), None) => SpotifyId::from_base62(id).ok().map(Media::Episode),
+        _ => None,
+    }
+}
+
+impl Metadata for Playlist {
+    type Message = protocol::playlist4changes::SelectedListContent;
+
+    fn request_url(id: SpotifyId) -> String {
+        format!("hm://playlist/v2/playlist/{}", id.to_base62())
+    }
+
+    fn parse(msg: &Self::Message, _: &Session) -> Self {
+        let name = msg.get_attributes().get_name().to_owned();
+        let items = msg.get_contents().get_items().iter()
+            .filter_map(|item| {
+                let media = parse_item(item.get_uri());
+                if media.is_none() {
+                    warn!("Skipping {} in playlist {}", item.get_uri(), name);
+                }
+                media
+            })
+            .collect();
+        Playlist { name, items }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6rqhFgbbKwnb9MLmUQDhG6";
+
+    #[test]
+    fn parses_tracks_and_episodes() {
+        let id = SpotifyId::from_base62(ID).unwrap();
+        assert!(matches!(parse_item(&format!("spotify:track:{}", ID)), Some(Media::Track(track)) if track == id));
+        assert!(matches!(parse_item(&format!("spotify:episode:{}", ID)), Some(Media::Episode(episode)) if episode == id));
+    }
+
+    #[test]
+    fn skips_local_files() {
+        assert!(parse_item("spotify:local:Artist:Album:Title:180").is_none());
+        assert!(parse_item("spotify:local:::Title:0").is_none());
+        assert!(parse_item("").is_none());
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use librespot_core::session::Session;
+use librespot_core::spotify_id::{FileId, SpotifyId};
+use librespot_metadata::{FileFormat, Metadata};
+use librespot_protocol as protocol;
+
+/// A podcast episode, with the publish date that `librespot_metadata::Episode` does not expose.
+pub struct Episode {
+    pub name: String,
+    /// `None` when the metadata does not name a valid show
+    pub show: Option<SpotifyId>,
+    /// `YYYY-MM-DD`, `None` when the episode has no publish date
+    pub publish_date: Option<String>,
+    pub files: HashMap<FileFormat, FileId>,
+}
+
+impl Metadata for Episode {
+    type Message = protocol::metadata::Episode;
+
+    fn request_url(id: SpotifyId) -> String {
+        format!("hm://metadata/3/episode/{}", id.to_base16())
+    }
+
+    fn parse(msg: &Self::Message, _: &Session) -> Self {
+        let files = msg.get_file().iter()
+            .filter(|file| file.has_file_id())
+            .map(|file| {
+                let mut dst = [0u8; 20];
+                dst.clone_from_slice(file.get_file_id());
+                (file.get_format(), FileId(dst))
+            })
+            .collect();
+        let date = msg.get_publish_time();
+        let publish_date = Some(date)
+            .filter(|date| msg.has_publish_time() && date.get_year() > 0)
+            .map(|date| format!("{:04}-{:02}-{:02}", date.get_year(), date.get_month(), date.get_day()));
+
+        Episode {
+            name: msg.get_name().to_owned(),
+            show: SpotifyId::from_raw(msg.get_show().get_gid()).ok(),
+            publish_date,
+            files,
+        }
+    }
+}
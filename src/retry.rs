@@ -0,0 +1,83 @@
+use std::cmp;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+use librespot_core::session::Session;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+/// How Spotify requests are retried, the `[retry]` table of `oggify.toml`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled at each further retry
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Time after which a single attempt is abandoned
+    pub timeout_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Awaits the future returned by `operation` until it succeeds, at most `attempts` times.
+    /// The error is the description of the last failure.
+    pub async fn run<F, R, T, E>(&self, what: &str, operation: F) -> Result<T, String>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = Result<T, E>>,
+        E: Debug,
+    {
+        self.run_until(what, operation, || false).await
+    }
+
+    /// Like `run` for a request made with `session`, but gives up as soon as the session is
+    /// invalid, as retrying is then pointless until it is reconnected.
+    pub async fn run_in<F, R, T, E>(&self, session: &Session, what: &str, operation: F) -> Result<T, String>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = Result<T, E>>,
+        E: Debug,
+    {
+        self.run_until(what, operation, || session.is_invalid()).await
+    }
+
+    async fn run_until<F, R, T, E>(&self, what: &str, mut operation: F, give_up: impl Fn() -> bool) -> Result<T, String>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = Result<T, E>>,
+        E: Debug,
+    {
+        let mut backoff = self.initial_backoff_ms;
+        for attempt in 1.. {
+            let error = match time::timeout(Duration::from_secs(self.timeout_secs), operation()).await {
+                Ok(Ok(item)) => return Ok(item),
+                Ok(Err(e)) => format!("{:?}", e),
+                Err(_) => format!("timed out after {}s", self.timeout_secs),
+            };
+            if attempt >= self.attempts || give_up() {
+                return Err(error);
+            }
+
+            // Equal jitter: half of the backoff is fixed, the other half random
+            let delay = backoff / 2 + rand::thread_rng().gen_range(0, backoff / 2 + 1);
+            warn!("{} failed ({}), retrying in {}ms ({}/{})", what, error, delay, attempt, self.attempts);
+            time::sleep(Duration::from_millis(delay)).await;
+            backoff = cmp::min(backoff.saturating_mul(2), self.max_backoff_ms);
+        }
+        unreachable!()
+    }
+}
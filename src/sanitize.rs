@@ -0,0 +1,134 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest file name most file systems accept, in bytes.
+const MAX_COMPONENT_BYTES: usize = 255;
+
+/// Characters that are not allowed in file names on Windows, besides control characters.
+const RESERVED_CHARS: [char; 9] = ['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names that Windows reserves, with any extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that `replacement` can itself appear in a file name.
+pub fn validate_replacement(replacement: char) -> Result<(), String> {
+    if RESERVED_CHARS.contains(&replacement) || replacement.is_control() || replacement == '.' {
+        Err(format!("{:?} cannot be used as replacement character in file names", replacement))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes `name` usable as a single path component on Linux, macOS and Windows. Separators,
+/// reserved and control characters become `replacement`, leading dots and surrounding spaces
+/// are removed, reserved device names are prefixed with `replacement`, and the name is
+/// normalized to NFC and shortened to 255 bytes, keeping its extension.
+pub fn sanitize_component(name: &str, replacement: char) -> String {
+    let name: String = name.nfc()
+        .map(|c| if RESERVED_CHARS.contains(&c) || c.is_control() { replacement } else { c })
+        .collect();
+    // Leading dots make hidden files, or the . and .. components; Windows drops trailing dots
+    let mut name = name.trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_owned();
+
+    let stem = name.split('.').next().unwrap_or("");
+    if RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        name.insert(0, replacement);
+    }
+
+    if name.len() > MAX_COMPONENT_BYTES {
+        let (stem, extension) = split_extension(&name);
+        name = format!("{}{}", truncate(stem, MAX_COMPONENT_BYTES - extension.len()), extension);
+    }
+    if name.is_empty() {
+        name.push(replacement);
+    }
+    name
+}
+
+/// Splits a short extension, including its dot, from `name`.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// Cuts `stem` to at most `max` bytes on a character boundary.
+fn truncate(stem: &str, max: usize) -> &str {
+    if stem.len() <= max {
+        return stem;
+    }
+    let mut end = max;
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    stem[..end].trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+}
+
+/// Inserts ` (suffix)` before the extension of the sanitized `name`, shortening the name if
+/// needed so that the suffix is kept.
+pub fn with_suffix(name: &str, suffix: &str) -> String {
+    let (stem, extension) = split_extension(name);
+    let suffix = format!(" ({}){}", suffix, extension);
+    format!("{}{}", truncate(stem, MAX_COMPONENT_BYTES.saturating_sub(suffix.len())), suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_reserved_characters() {
+        assert_eq!(sanitize_component("a/b\\c:d*e?f\"g<h>i|j\u{7}k", '_'), "a_b_c_d_e_f_g_h_i_j_k");
+        assert_eq!(sanitize_component("AC/DC", '-'), "AC-DC");
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        assert_eq!(sanitize_component("Cafe\u{301}", '_'), "Caf\u{e9}");
+    }
+
+    #[test]
+    fn trims_dots_and_spaces() {
+        assert_eq!(sanitize_component("..hidden", '_'), "hidden");
+        assert_eq!(sanitize_component(" . name. ", '_'), "name");
+        assert_eq!(sanitize_component("..", '_'), "_");
+        assert_eq!(sanitize_component("", '_'), "_");
+    }
+
+    #[test]
+    fn prefixes_reserved_names() {
+        assert_eq!(sanitize_component("CON", '_'), "_CON");
+        assert_eq!(sanitize_component("lpt1.ogg", '_'), "_lpt1.ogg");
+        assert_eq!(sanitize_component("Console.ogg", '_'), "Console.ogg");
+    }
+
+    #[test]
+    fn cuts_long_names_on_a_char_boundary() {
+        // Two bytes per character, so the 251 bytes left for the stem end in the middle of one
+        let name = sanitize_component(&format!("{}.ogg", "\u{e9}".repeat(200)), '_');
+        assert_eq!(name.len(), 254);
+        assert!(name.ends_with("\u{e9}.ogg"));
+    }
+
+    #[test]
+    fn with_suffix_stays_within_the_limit() {
+        assert_eq!(with_suffix("title.ogg", "2"), "title (2).ogg");
+        let name = with_suffix(&format!("{}.ogg", "a".repeat(251)), "4uNfa1sAVMT8HJvDcUbRgr");
+        assert_eq!(name.len(), MAX_COMPONENT_BYTES);
+        assert!(name.ends_with(" (4uNfa1sAVMT8HJvDcUbRgr).ogg"));
+    }
+
+    #[test]
+    fn validates_replacement() {
+        assert!(validate_replacement('_').is_ok());
+        assert!(validate_replacement('/').is_err());
+        assert!(validate_replacement('.').is_err());
+        assert!(validate_replacement('\n').is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::sanitize::sanitize_component;
+
+/// The fields a template can refer to.
+pub const FIELDS: [&str; 10] =
+    ["title", "artists", "artist", "album", "album_artist", "year", "date", "disc", "track", "id"];
+
+/// The fields that can be zero padded to a width.
+const NUMERIC_FIELDS: [&str; 3] = ["year", "disc", "track"];
+
+enum Part {
+    Literal(String),
+    /// A field, zero padded to the width if it is numeric and there is one
+    Field(String, usize),
+    Separator,
+}
+
+/// A path relative to the output directory, with `{field}` or `{field:02}` placeholders and
+/// `/` between directories, for example `{album_artist}/{year} - {album}/{disc:02}-{track:02} {title}.ogg`.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Err(format!("Template {} must be relative to the output directory", s));
+        }
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("Unclosed {{{} in template {}", placeholder, s)),
+                        }
+                    }
+                    let mut spec = placeholder.splitn(2, ':');
+                    let name = spec.next().unwrap_or_default();
+                    if !FIELDS.contains(&name) {
+                        return Err(format!("Unknown field {{{}}} in template, use one of {}", placeholder, FIELDS.join(", ")));
+                    }
+                    let width = match spec.next() {
+                        Some(_) if !NUMERIC_FIELDS.contains(&name) =>
+                            return Err(format!("Only {} can have a width, not {{{}}}", NUMERIC_FIELDS.join(", "), placeholder)),
+                        Some(width) => width.parse().map_err(|_| format!("Invalid width in {{{}}}", placeholder))?,
+                        None => 0,
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(name.to_owned(), width));
+                }
+                '/' => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Separator);
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    /// Fills in the template. Missing fields are left empty, and each directory and file name is
+    /// sanitized, so that a `/` in a value does not start a new directory.
+    pub fn render(&self, fields: &HashMap<&str, String>, replacement: char) -> PathBuf {
+        let mut path = PathBuf::new();
+        let mut component = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => component.push_str(literal),
+                Part::Field(name, width) => {
+                    let value = fields.get(name.as_str()).map(String::as_str).unwrap_or("");
+                    component.push_str(&format!("{:0>width$}", value, width = width));
+                }
+                Part::Separator => path.push(sanitize_component(&std::mem::take(&mut component), replacement)),
+            }
+        }
+        path.push(sanitize_component(&component, replacement));
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> HashMap<&'static str, String> {
+        let mut fields = HashMap::new();
+        fields.insert("title", "Song: Part 1/2".to_owned());
+        fields.insert("artists", "A, B".to_owned());
+        fields.insert("album", "..Album".to_owned());
+        fields.insert("year", "1999".to_owned());
+        fields.insert("disc", "1".to_owned());
+        fields.insert("track", "7".to_owned());
+        fields
+    }
+
+    fn render(template: &str) -> PathBuf {
+        template.parse::<Template>().unwrap().render(&fields(), '_')
+    }
+
+    #[test]
+    fn renders_fields_and_directories() {
+        assert_eq!(render("{artists} - {title}.ogg"), PathBuf::from("A, B - Song_ Part 1_2.ogg"));
+        assert_eq!(render("{album} ({year})/{disc}-{track:02} {title}.ogg"), PathBuf::from("Album (1999)/1-07 Song_ Part 1_2.ogg"));
+    }
+
+    #[test]
+    fn missing_fields_are_empty() {
+        assert_eq!(render("{artist}/{track:03}.ogg"), PathBuf::from("_/007.ogg"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!("/{title}.ogg".parse::<Template>().is_err());
+        assert!("{name}.ogg".parse::<Template>().is_err());
+        assert!("{title.ogg".parse::<Template>().is_err());
+        assert!("{track:xx}.ogg".parse::<Template>().is_err());
+        assert!("{title:10}.ogg".parse::<Template>().is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use librespot_core::session::Session;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_metadata::{Metadata, Track};
+use librespot_protocol as protocol;
+
+/// A track, with the fields that `librespot_metadata::Track` does not expose, read from the
+/// same message.
+pub struct TrackDetails {
+    pub track: Track,
+    pub number: i32,
+    pub disc_number: i32,
+    pub isrc: Option<String>,
+    pub album: String,
+    pub album_artists: Vec<String>,
+    /// Release date of the album, `YYYY`, `YYYY-MM` or `YYYY-MM-DD` depending on its precision
+    pub date: String,
+}
+
+impl Metadata for TrackDetails {
+    type Message = protocol::metadata::Track;
+
+    fn request_url(id: SpotifyId) -> String {
+        format!("hm://metadata/3/track/{}", id.to_base16())
+    }
+
+    fn parse(msg: &Self::Message, session: &Session) -> Self {
+        let album = msg.get_album();
+        let date = album.get_date();
+        let date = match (date.get_year(), date.get_month(), date.get_day()) {
+            (0, _, _) => String::new(),
+            (year, 0, _) => format!("{:04}", year),
+            (year, month, 0) => format!("{:04}-{:02}", year, month),
+            (year, month, day) => format!("{:04}-{:02}-{:02}", year, month, day),
+        };
+
+        TrackDetails {
+            track: Track::parse(msg, session),
+            number: msg.get_number(),
+            disc_number: msg.get_disc_number(),
+            isrc: msg.get_external_id().iter()
+                .find(|external_id| external_id.get_field_type().eq_ignore_ascii_case("isrc"))
+                .map(|external_id| external_id.get_id().to_owned()),
+            album: album.get_name().to_owned(),
+            album_artists: album.get_artist().iter().map(|artist| artist.get_name().to_owned()).collect(),
+            date,
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Packets larger than this are not expected in the headers of the files we write.
+const MAX_HEADER_PACKET: usize = 16 * 1024 * 1024;
+
+/// A Vorbis comment, as field name and value.
+pub type Comment = (String, String);
+
+/// Header type flag of a page that starts with the rest of a packet from the previous page.
+const CONTINUED: u8 = 0x01;
+
+lazy_static! {
+    /// CRC-32 lookup table for the Ogg polynomial 0x04c11db7, without bit reflection.
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = (i as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize])
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// An Ogg page, with its lacing values and data.
+struct Page {
+    header_type: u8,
+    granule_position: u64,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Page {
+    /// Reads the next page of `reader`, or `None` at the end of the stream.
+    fn read(reader: &mut impl Read) -> io::Result<Option<Page>> {
+        let mut header = [0u8; 27];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        reader.read_exact(&mut header[1..])?;
+        if &header[..4] != b"OggS" {
+            return Err(invalid("missing Ogg page capture pattern"));
+        }
+        let mut segments = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut segments)?;
+        let mut body = vec![0u8; segments.iter().map(|&len| len as usize).sum()];
+        reader.read_exact(&mut body)?;
+        let mut granule_position = [0u8; 8];
+        granule_position.copy_from_slice(&header[6..14]);
+        Ok(Some(Page {
+            header_type: header[5],
+            granule_position: u64::from_le_bytes(granule_position),
+            serial: u32::from_le_bytes([header[14], header[15], header[16], header[17]]),
+            sequence: u32::from_le_bytes([header[18], header[19], header[20], header[21]]),
+            segments,
+            body,
+        }))
+    }
+
+    /// The page as written to a file, with its checksum.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(27 + self.segments.len() + self.body.len());
+        bytes.extend_from_slice(b"OggS");
+        bytes.push(0);
+        bytes.push(self.header_type);
+        bytes.extend_from_slice(&self.granule_position.to_le_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(self.segments.len() as u8);
+        bytes.extend_from_slice(&self.segments);
+        bytes.extend_from_slice(&self.body);
+        let crc = crc32(&bytes);
+        bytes[22..26].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// Reads the first `count` packets of the Ogg stream in `reader`. Also returns the pages they
+/// span, and whether the last of them ends with the last packet.
+fn read_packets(reader: &mut impl Read, count: usize) -> io::Result<(Vec<Vec<u8>>, Vec<Page>, bool)> {
+    let mut packets = Vec::new();
+    let mut pages = Vec::new();
+    let mut packet = Vec::new();
+    while packets.len() < count {
+        let page = Page::read(reader)?.ok_or_else(|| invalid("stream ends within the headers"))?;
+        let mut offset = 0;
+        let mut ends_with_packet = false;
+        for (index, &len) in page.segments.iter().enumerate() {
+            packet.extend_from_slice(&page.body[offset..offset + len as usize]);
+            offset += len as usize;
+            if packet.len() > MAX_HEADER_PACKET {
+                return Err(invalid("header packet too large"));
+            }
+            // A segment shorter than 255 bytes ends the packet
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+                if packets.len() == count {
+                    ends_with_packet = index == page.segments.len() - 1;
+                    break;
+                }
+            }
+        }
+        pages.push(page);
+        if packets.len() == count {
+            return Ok((packets, pages, ends_with_packet));
+        }
+    }
+    Ok((packets, pages, true))
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let bytes = data.get(*pos..*pos + 4).ok_or_else(|| invalid("truncated comment header"))?;
+    *pos += 4;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+fn read_string<'a>(data: &'a [u8], pos: &mut usize) -> io::Result<&'a [u8]> {
+    let len = read_u32(data, pos)?;
+    let bytes = data.get(*pos..*pos + len).ok_or_else(|| invalid("truncated comment header"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Splits a comment header packet into the vendor string and the comments, with upper case
+/// field names.
+fn parse_comment_header(header: &[u8]) -> io::Result<(Vec<u8>, Vec<Comment>)> {
+    if !header.starts_with(b"\x03vorbis") {
+        return Err(invalid("missing Vorbis comment header"));
+    }
+    let mut pos = 7;
+    let vendor = read_string(header, &mut pos)?.to_vec();
+    let count = read_u32(header, &mut pos)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let comment = String::from_utf8_lossy(read_string(header, &mut pos)?).into_owned();
+        if let Some(separator) = comment.find('=') {
+            comments.push((comment[..separator].to_uppercase(), comment[separator + 1..].to_owned()));
+        }
+    }
+    Ok((vendor, comments))
+}
+
+fn comment_header(vendor: &[u8], comments: &[Comment]) -> Vec<u8> {
+    let mut header = b"\x03vorbis".to_vec();
+    header.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    header.extend_from_slice(vendor);
+    header.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (field, value) in comments {
+        let comment = format!("{}={}", field, value);
+        header.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        header.extend_from_slice(comment.as_bytes());
+    }
+    // Framing bit
+    header.push(1);
+    header
+}
+
+/// The Vorbis comments of the Ogg file at `path`, with upper case field names.
+pub fn read_comments(path: &Path) -> io::Result<Vec<Comment>> {
+    let (packets, _, _) = read_packets(&mut BufReader::new(File::open(path)?), 2)?;
+    parse_comment_header(&packets[1]).map(|(_, comments)| comments)
+}
+
+/// The `SPOTIFY_ID` comment of the Ogg file at `path`, if it has one.
+pub fn spotify_id(path: &Path) -> Option<String> {
+    read_comments(path).ok()?.into_iter()
+        .find(|(field, _)| field == "SPOTIFY_ID")
+        .map(|(_, value)| value)
+}
+
+/// Lays out `packets` on pages of stream `serial`, numbered from `sequence`. The last page ends
+/// with the last packet.
+fn paginate(packets: &[Vec<u8>], serial: u32, mut sequence: u32) -> Vec<Page> {
+    let new_page = |sequence, header_type| Page {
+        header_type,
+        // Pages on which no packet ends have no granule position
+        granule_position: u64::MAX,
+        serial,
+        sequence,
+        segments: Vec::new(),
+        body: Vec::new(),
+    };
+    let mut pages = Vec::new();
+    let mut page = new_page(sequence, 0);
+    for packet in packets {
+        // Lacing values of 255 continue the packet, the last one is shorter, possibly 0
+        let mut chunks: Vec<&[u8]> = packet.chunks(255).collect();
+        if packet.len() % 255 == 0 {
+            chunks.push(&[]);
+        }
+        for (index, chunk) in chunks.iter().enumerate() {
+            if page.segments.len() == 255 {
+                sequence += 1;
+                let continued = if index > 0 { CONTINUED } else { 0 };
+                pages.push(std::mem::replace(&mut page, new_page(sequence, continued)));
+            }
+            page.segments.push(chunk.len() as u8);
+            page.body.extend_from_slice(chunk);
+        }
+        // Header packets have granule position 0
+        page.granule_position = 0;
+    }
+    pages.push(page);
+    pages
+}
+
+/// A reader passing through an Ogg Vorbis stream with its comment header replaced. The given
+/// comments replace the existing ones with the same field names, the others are kept. The header
+/// packets are laid out on new pages, and the following pages are renumbered.
+pub struct Tagger<R> {
+    inner: R,
+    comments: Vec<Comment>,
+    headers_done: bool,
+    /// Difference between the new and the original sequence numbers of the audio pages
+    sequence_shift: i64,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> Tagger<R> {
+    pub fn new(inner: R, comments: Vec<Comment>) -> Self {
+        Tagger { inner, comments, headers_done: false, sequence_shift: 0, pending: Vec::new(), position: 0 }
+    }
+
+    fn rewrite_headers(&mut self) -> io::Result<()> {
+        let (mut packets, pages, ends_with_packet) = read_packets(&mut self.inner, 3)?;
+        if !ends_with_packet {
+            return Err(invalid("audio data shares a page with the Vorbis headers"));
+        }
+        if pages[0].segments.len() != 1 {
+            return Err(invalid("identification header does not have its own page"));
+        }
+        let (vendor, existing) = parse_comment_header(&packets[1])?;
+        let mut comments: Vec<_> = existing.into_iter()
+            .filter(|(field, _)| !self.comments.iter().any(|(new_field, _)| new_field == field))
+            .collect();
+        comments.append(&mut self.comments);
+        packets[1] = comment_header(&vendor, &comments);
+
+        let first = &pages[0];
+        let mut new_pages = vec![Page {
+            header_type: first.header_type,
+            granule_position: first.granule_position,
+            serial: first.serial,
+            sequence: first.sequence,
+            segments: first.segments.clone(),
+            body: first.body.clone(),
+        }];
+        new_pages.extend(paginate(&packets[1..], first.serial, first.sequence + 1));
+        self.sequence_shift = new_pages.len() as i64 - pages.len() as i64;
+        for page in new_pages {
+            self.pending.extend(page.to_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Tagger<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            self.pending.clear();
+            self.position = 0;
+            if !self.headers_done {
+                self.rewrite_headers()?;
+                self.headers_done = true;
+            } else if let Some(mut page) = Page::read(&mut self.inner)? {
+                page.sequence = (page.sequence as i64 + self.sequence_shift) as u32;
+                self.pending = page.to_bytes();
+            }
+        }
+        let len = buf.len().min(self.pending.len() - self.position);
+        buf[..len].copy_from_slice(&self.pending[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An identification header page, checksum computed independently.
+    const IDENTIFICATION_PAGE: [u8; 58] = [
+        0x4f, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0xad, 0x5c, 0xe1, 0x01, 0x1e, 0x01, 0x76, 0x6f, 0x72,
+        0x62, 0x69, 0x73, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+        0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16,
+    ];
+
+    /// A page of stream 0x1234 holding whole `packets`.
+    fn page(sequence: u32, granule_position: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut page = Page { header_type: 0, granule_position, serial: 0x1234, sequence, segments: Vec::new(), body: Vec::new() };
+        for packet in packets {
+            page.segments.extend(vec![255; packet.len() / 255]);
+            page.segments.push((packet.len() % 255) as u8);
+            page.body.extend_from_slice(packet);
+        }
+        page.to_bytes()
+    }
+
+    fn read_pages(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while let Some(page) = Page::read(&mut data).unwrap() {
+            pages.push(page);
+        }
+        pages
+    }
+
+    fn tag(stream: &[u8], comments: &[(&str, &str)]) -> io::Result<Vec<u8>> {
+        let comments = comments.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect();
+        let mut tagged = Vec::new();
+        Tagger::new(stream, comments).read_to_end(&mut tagged)?;
+        Ok(tagged)
+    }
+
+    /// A stream with the headers on two pages, followed by two audio pages.
+    fn stream(setup: &[u8]) -> Vec<u8> {
+        let comments = comment_header(b"Xiph.Org libVorbis", &[("title".to_owned(), "Old".to_owned()), ("GENRE".to_owned(), "Jazz".to_owned())]);
+        let mut stream = IDENTIFICATION_PAGE.to_vec();
+        stream.extend(page(1, 0, &[&comments, setup]));
+        stream.extend(page(2, 4096, &[&[0xaa; 100], &[0xbb; 300]]));
+        stream.extend(page(3, 8192, &[&[0xcc; 50]]));
+        stream
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn known_page() {
+        let page = Page::read(&mut &IDENTIFICATION_PAGE[..]).unwrap().unwrap();
+        assert_eq!((page.header_type, page.serial, page.sequence), (2, 0x1234, 0));
+        assert_eq!(page.body.len(), 30);
+        assert_eq!(page.to_bytes(), IDENTIFICATION_PAGE.to_vec());
+        assert!(Page::read(&mut &b"OggT"[..]).is_err());
+        assert!(Page::read(&mut &b""[..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn tagger_round_trip() {
+        let stream = stream(&[5; 600]);
+        let tagged = tag(&stream, &[("TITLE", "New"), ("ARTIST", "A"), ("ARTIST", "B")]).unwrap();
+
+        let (packets, _, _) = read_packets(&mut &tagged[..], 3).unwrap();
+        assert_eq!(packets[0], IDENTIFICATION_PAGE[28..].to_vec());
+        let (vendor, comments) = parse_comment_header(&packets[1]).unwrap();
+        assert_eq!(vendor, b"Xiph.Org libVorbis");
+        let expected = [("GENRE", "Jazz"), ("TITLE", "New"), ("ARTIST", "A"), ("ARTIST", "B")];
+        assert_eq!(comments, expected.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect::<Vec<_>>());
+        assert_eq!(packets[2], vec![5; 600]);
+
+        // Headers and audio keep their pages, with valid checksums
+        let (original, pages) = (read_pages(&stream), read_pages(&tagged));
+        assert_eq!(pages.len(), original.len());
+        assert_eq!(pages.iter().map(|page| page.to_bytes()).collect::<Vec<_>>().concat(), tagged);
+        assert_eq!(tagged[tagged.len() - pages[3].to_bytes().len()..], stream[stream.len() - original[3].to_bytes().len()..]);
+    }
+
+    #[test]
+    fn packets_of_multiples_of_255_bytes() {
+        let pages = paginate(&[vec![1; 510], vec![2; 255]], 0x1234, 1);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].segments, vec![255, 255, 0, 255, 0]);
+
+        let stream = stream(&[5; 255 * 3]);
+        let tagged = tag(&stream, &[("TITLE", "New")]).unwrap();
+        let (packets, _, _) = read_packets(&mut &tagged[..], 3).unwrap();
+        assert_eq!(packets[2], vec![5; 255 * 3]);
+    }
+
+    #[test]
+    fn headers_spanning_pages() {
+        let stream = stream(&[5; 600]);
+        let long = "x".repeat(70_000);
+        let tagged = tag(&stream, &[("LYRICS", &long)]).unwrap();
+        let pages = read_pages(&tagged);
+        // The comment header is longer than the 255 segments of a page
+        assert_eq!(pages.len(), 5);
+        assert_eq!(pages[1].header_type, 0);
+        assert_eq!(pages[1].granule_position, u64::MAX);
+        assert_eq!(pages[2].header_type, CONTINUED);
+        assert_eq!(pages[2].granule_position, 0);
+        assert_eq!(pages.iter().map(|page| page.sequence).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(pages[3].body, read_pages(&stream)[2].body);
+
+        let (packets, _, _) = read_packets(&mut &tagged[..], 3).unwrap();
+        let (_, comments) = parse_comment_header(&packets[1]).unwrap();
+        assert!(comments.contains(&("LYRICS".to_owned(), long)));
+        assert_eq!(packets[2], vec![5; 600]);
+    }
+
+    #[test]
+    fn audio_sharing_a_page_with_headers() {
+        let comments = comment_header(b"vendor", &[]);
+        let mut stream = IDENTIFICATION_PAGE.to_vec();
+        stream.extend(page(1, 4096, &[&comments, &[5; 10], &[0xaa; 100]]));
+        let e = tag(&stream, &[("TITLE", "New")]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}